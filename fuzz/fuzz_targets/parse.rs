@@ -0,0 +1,12 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate ilvm;
+
+// The parser must reject any input it cannot parse with an error, and never
+// panic.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(source) = std::str::from_utf8(data) {
+        let _ = ilvm::parser::parse(source);
+    }
+});
@@ -1,4 +1,5 @@
 use std::io;
+use syntax::Pos;
 
 #[derive(Debug)]
 pub enum Error {
@@ -6,6 +7,41 @@ pub enum Error {
     Usage(String),
     Parse(String),
     Runtime(String),
+    // The runtime errors below have the block that the task was running and
+    // the source position of the instruction that failed, when they are
+    // known.
+    Abort {
+        reason: Option<String>,
+        block: Option<i32>,
+        pos: Option<Pos>,
+    },
+    DivByZero {
+        block: Option<i32>,
+        pos: Option<Pos>,
+    },
+    OutOfBounds {
+        addr: usize,
+        block: Option<i32>,
+        pos: Option<Pos>,
+    },
+    // `available` is the total free space, which may be fragmented.
+    OutOfMemory {
+        requested: usize,
+        available: usize,
+        block: Option<i32>,
+        pos: Option<Pos>,
+    },
+    DoubleFree {
+        addr: usize,
+        block: Option<i32>,
+        pos: Option<Pos>,
+    },
+    // A jump to a block that does not exist.
+    UndefinedBlock {
+        id: i32,
+        block: Option<i32>,
+        pos: Option<Pos>,
+    },
 }
 
 impl From<io::Error> for Error {
@@ -16,22 +52,61 @@ impl From<io::Error> for Error {
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let (block, pos) = match self {
+            Error::Abort { block, pos, .. }
+            | Error::DivByZero { block, pos }
+            | Error::OutOfBounds { block, pos, .. }
+            | Error::OutOfMemory { block, pos, .. }
+            | Error::DoubleFree { block, pos, .. }
+            | Error::UndefinedBlock { block, pos, .. } => (*block, *pos),
+            _ => (None, None),
+        };
+        match (block, pos) {
+            (Some(block), Some(pos)) => {
+                write!(f, "block {}, {}: ", block, pos)?
+            }
+            (Some(block), None) => write!(f, "block {}: ", block)?,
+            (None, Some(pos)) => write!(f, "{}: ", pos)?,
+            (None, None) => (),
+        }
         match self {
             Error::IO(e) => e.fmt(f),
             Error::Usage(s) => f.write_str(s),
             Error::Parse(s) => f.write_str(s),
             Error::Runtime(s) => f.write_str(s),
+            Error::Abort {
+                reason: Some(reason),
+                ..
+            } => write!(f, "called abort: {}", reason),
+            Error::Abort { .. } => f.write_str("called abort"),
+            Error::DivByZero { .. } => f.write_str("division by zero"),
+            Error::OutOfBounds { addr, .. } => {
+                write!(f, "invalid address {}", addr)
+            }
+            Error::OutOfMemory {
+                requested,
+                available,
+                ..
+            } => write!(
+                f,
+                "malloc({}) is out of memory, with {} words free",
+                requested, available
+            ),
+            Error::DoubleFree { addr, .. } => {
+                write!(f, "double free of {}", addr)
+            }
+            Error::UndefinedBlock { id, .. } => {
+                write!(f, "undefined block {}", id)
+            }
         }
     }
 }
 
 impl std::error::Error for Error {
-    fn description(&self) -> &str {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
-            Error::IO(e) => e.description(),
-            Error::Usage(s) => s,
-            Error::Parse(s) => s,
-            Error::Runtime(s) => s,
+            Error::IO(e) => Some(e),
+            _ => None,
         }
     }
 }
@@ -1,20 +1,349 @@
 use error::Error;
-use std::collections::HashMap;
-use syntax::{Instr, Op2, Val, Printable};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::hash::{BuildHasherDefault, Hasher};
+use std::io::{self, BufRead, Write};
+use std::time::{Duration, Instant};
+use syntax::{Instr, Op1, Op2, Pos, Printable, SourceMap, Val};
+use tc;
 
 enum FreeList {
     Nil,
     Node(usize, usize, Box<FreeList>),
 }
-struct State {
-    heap: Vec<i32>,
+// The heap that the program loads from and stores to. Implementations may
+// fail an access with a message, which becomes a runtime error.
+pub trait Memory {
+    fn read(&mut self, addr: usize) -> Result<i32, String>;
+    fn write(&mut self, addr: usize, val: i32) -> Result<(), String>;
+    // `Machine::new` rejects a heap shorter than `MIN_HEAP_SIZE`.
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Every word at or past this address is 0, if the program never wrote
+    // there.
+    fn used_len(&self) -> usize {
+        self.len()
+    }
+}
+
+impl Memory for Vec<i32> {
+    fn read(&mut self, addr: usize) -> Result<i32, String> {
+        self.get(addr)
+            .cloned()
+            .ok_or_else(|| format!("invalid address {}", addr))
+    }
+
+    fn write(&mut self, addr: usize, val: i32) -> Result<(), String> {
+        match self.get_mut(addr) {
+            Some(cell) => {
+                *cell = val;
+                Ok(())
+            }
+            None => Err(format!("invalid address {}", addr)),
+        }
+    }
+
+    fn len(&self) -> usize {
+        Vec::len(self)
+    }
+}
+
+// A heap of `limit` words that only stores the words up to the highest one
+// written so far, so that a large limit costs nothing until the program uses
+// the memory. The other words read as 0.
+pub struct LazyHeap {
+    cells: Vec<i32>,
+    limit: usize,
+}
+
+impl LazyHeap {
+    pub fn new(limit: usize) -> Self {
+        LazyHeap {
+            cells: Vec::new(),
+            limit,
+        }
+    }
+}
+
+impl Memory for LazyHeap {
+    fn read(&mut self, addr: usize) -> Result<i32, String> {
+        if addr >= self.limit {
+            return Err(format!("invalid address {}", addr));
+        }
+        Ok(self.cells.get(addr).cloned().unwrap_or(0))
+    }
+
+    fn write(&mut self, addr: usize, val: i32) -> Result<(), String> {
+        if addr >= self.limit {
+            return Err(format!("invalid address {}", addr));
+        }
+        if addr >= self.cells.len() {
+            self.cells.resize(addr + 1, 0);
+        }
+        self.cells[addr] = val;
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.limit
+    }
+
+    fn used_len(&self) -> usize {
+        self.cells.len()
+    }
+}
+
+struct State<'t> {
+    heap: Box<dyn Memory>,
     registers: Vec<i32>,
     free_list: FreeList,
-    alloc_blocks: HashMap<usize, usize>,
+    // Ordered by base address, so that nothing depends on hash order.
+    alloc_blocks: BTreeMap<usize, usize>,
+    // Blocks that were freed and not handed out again, by base address.
+    freed_blocks: BTreeMap<usize, usize>,
+    // With strict bounds, the base of the block that each register's pointer
+    // was derived from, and the same for pointers stored on the heap.
+    origins: Vec<Option<usize>>,
+    heap_origins: HashMap<usize, usize>,
+    records: Records,
+    // The block that control most recently entered, used to locate
+    // instructions for error messages.
+    block: i32,
+    // Blocks passed to `spawn` that have not been scheduled yet.
+    spawned: Vec<i32>,
+    stats: Stats,
+    io: Io<'t>,
+}
+
+// Where a running program's input comes from and its output goes.
+pub struct Io<'a> {
+    // Supplies the integers that `read` consumes.
+    pub input: &'a mut dyn BufRead,
+    // Receives the output of `print`.
+    pub out: &'a mut dyn Write,
+    // Receives a description of each instruction before it runs, if any.
+    pub trace: Option<&'a mut dyn Write>,
+    // Receives the registers and the live heap blocks if the program stops
+    // with an error, if any.
+    pub dump: Option<&'a mut dyn Write>,
 }
 
-struct Env {
-    instructions: HashMap<i32, Instr>,
+// The blocks that jumps can go to. Block IDs are usually dense, so a vector
+// indexed by ID finds them without hashing on every `goto` and `call`. IDs
+// that are far apart would make the vector too large, so they use the map.
+struct Targets<'a> {
+    first: i32,
+    dense: Vec<Option<&'a Instr>>,
+    blocks: &'a HashMap<i32, Instr>,
+}
+
+impl<'a> Targets<'a> {
+    fn new(blocks: &'a HashMap<i32, Instr>) -> Self {
+        let first = blocks.keys().min().cloned().unwrap_or(0);
+        let last = blocks.keys().max().cloned().unwrap_or(0);
+        let span = i64::from(last) - i64::from(first) + 1;
+        let mut dense = Vec::new();
+        if span <= 2 * blocks.len() as i64 + 64 {
+            dense = vec![None; span as usize];
+            for (id, block) in blocks {
+                dense[(i64::from(*id) - i64::from(first)) as usize] =
+                    Some(block);
+            }
+        }
+        Targets {
+            first,
+            dense,
+            blocks,
+        }
+    }
+
+    fn get(&self, id: i32) -> Option<&'a Instr> {
+        if self.dense.is_empty() {
+            return self.blocks.get(&id);
+        }
+        let i = i64::from(id) - i64::from(self.first);
+        if i < 0 {
+            return None;
+        }
+        self.dense.get(i as usize).cloned().unwrap_or(None)
+    }
+}
+
+struct Env<'a> {
+    instructions: &'a HashMap<i32, Instr>,
+    op2s: Op2s<'a>,
+    targets: Targets<'a>,
+    positions: &'a SourceMap,
+    coroutines: bool,
+    strict_bounds: bool,
+    step_limit: Option<u64>,
+    // When the timeout runs out, if there is one.
+    deadline: Option<Instant>,
+    overflow: Overflow,
+    alloc: Alloc,
+    poison: bool,
+}
+
+// How many instructions run between looks at the clock, which is slow
+// compared to an instruction.
+const DEADLINE_CHECK_STEPS: u64 = 4096;
+
+// Values that the program recorded for inspection after it exits.
+#[derive(Default)]
+pub struct Records {
+    // The last value recorded by each `tag` instruction.
+    pub tags: HashMap<String, i32>,
+    // The heap contents copied by each `capture` instruction, in the order
+    // they ran.
+    pub captures: Vec<(String, Vec<i32>)>,
+}
+
+// Counts of what the program did, by every task, for profiling.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Stats {
+    pub instructions: u64,
+    pub mallocs: u64,
+    pub frees: u64,
+    // Words in allocated blocks, now and at most.
+    pub allocated: usize,
+    pub peak_allocated: usize,
+    // Words read from or written to the heap.
+    pub heap_accesses: u64,
+}
+
+// What arithmetic does when its result does not fit in 32 bits.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Overflow {
+    // Stop with a runtime error.
+    Checked,
+    // Wrap around, as two's complement hardware does.
+    Wrapping,
+}
+
+// Which free block `malloc` takes its words from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Alloc {
+    // The first one, in address order, that is large enough.
+    FirstFit,
+    // The smallest one that is large enough, which leaves larger blocks for
+    // later requests.
+    BestFit,
+}
+
+// The fewest words a heap can have, since `malloc` never returns address 0.
+pub const MIN_HEAP_SIZE: usize = 2;
+
+// What `malloc` fills blocks with when `Config::poison` is set.
+pub const POISON: i32 = 0xDEADBEEFu32 as i32;
+
+#[derive(Clone)]
+pub struct Config {
+    // The block where the program starts, which is usually block 0.
+    pub start: i32,
+    // The most words that the heap may grow to.
+    pub heap_size: usize,
+    pub num_registers: usize,
+    // Run `spawn`ed blocks as coroutines that take turns at each `yield`.
+    pub coroutines: bool,
+    // Check that `a[i]` indexes into the block that `a` points to, that a
+    // pointer computed from a block stays inside it, and that every other
+    // load and store is inside some allocated block.
+    pub strict_bounds: bool,
+    // Written to a heap block before the program starts. Block 0 begins with
+    // their count in r0 and the block's address in r1.
+    pub args: Vec<i32>,
+    // Stop with an error after running this many instructions.
+    pub step_limit: Option<u64>,
+    // Stop with an error after running for about this long.
+    pub timeout: Option<Duration>,
+    // Ignore `timeout`, so that how the run ends depends only on the
+    // program, its input, and the other settings.
+    pub deterministic: bool,
+    // Print each instruction to stderr before running it.
+    pub trace: bool,
+    // Print the registers and the live heap blocks to stderr if the program
+    // stops with an error.
+    pub dump_on_error: bool,
+    pub overflow: Overflow,
+    pub alloc: Alloc,
+    // Fill the blocks that `malloc` returns with `POISON` instead of leaving
+    // whatever was there, so that reading them before writing them shows.
+    pub poison: bool,
+    // Pause whenever a task reaches the start of one of these blocks.
+    pub breakpoints: Vec<i32>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            start: 0,
+            heap_size: 1024,
+            num_registers: 32,
+            coroutines: false,
+            strict_bounds: false,
+            args: Vec::new(),
+            step_limit: None,
+            timeout: None,
+            deterministic: false,
+            trace: false,
+            dump_on_error: false,
+            overflow: Overflow::Checked,
+            alloc: Alloc::FirstFit,
+            poison: false,
+            breakpoints: Vec::new(),
+        }
+    }
+}
+
+fn position(st: &State, env: &Env, instr: &Instr) -> Option<Pos> {
+    let root = env.instructions.get(&st.block)?;
+    let index = root
+        .preorder()
+        .into_iter()
+        .position(|other| std::ptr::eq(other, instr))?;
+    env.positions.get(&st.block)?.get(index).cloned()
+}
+
+// A runtime error raised by `instr`, which names the block that the task was
+// running and the source position of `instr` when it is known.
+fn runtime_error(st: &State, env: &Env, instr: &Instr, msg: String) -> Error {
+    match position(st, env, instr) {
+        Some(pos) => {
+            Error::Runtime(format!("block {}, {}: {}", st.block, pos, msg))
+        }
+        None => Error::Runtime(format!("block {}: {}", st.block, msg)),
+    }
+}
+
+// Adds the source position of `instr` to `error`, which `instr` raised.
+fn locate_error(st: &State, env: &Env, instr: &Instr, error: Error) -> Error {
+    match error {
+        Error::Runtime(msg) => runtime_error(st, env, instr, msg),
+        Error::DivByZero { .. } => Error::DivByZero {
+            block: Some(st.block),
+            pos: position(st, env, instr),
+        },
+        error => error,
+    }
+}
+
+fn out_of_bounds(st: &State, env: &Env, instr: &Instr, addr: usize) -> Error {
+    Error::OutOfBounds {
+        addr,
+        block: Some(st.block),
+        pos: position(st, env, instr),
+    }
+}
+
+fn undefined_block(st: &State, env: &Env, instr: &Instr, id: i32) -> Error {
+    Error::UndefinedBlock {
+        id,
+        block: Some(st.block),
+        pos: position(st, env, instr),
+    }
 }
 
 fn eval_val(reg: &[i32], v: &Val) -> i32 {
@@ -24,64 +353,588 @@ fn eval_val(reg: &[i32], v: &Val) -> i32 {
     }
 }
 
-fn eval_op2(op2: &Op2, m: i32, n: i32) -> i32 {
+// The result of `m op2 n`, or why there is none. Errors have no position.
+pub fn eval_op2(
+    op2: &Op2,
+    overflow: Overflow,
+    m: i32,
+    n: i32,
+) -> Result<i32, Error> {
+    let arith = |checked: fn(i32, i32) -> Option<i32>,
+                 wrapping: fn(i32, i32) -> i32| match overflow {
+        Overflow::Checked => checked(m, n)
+            .ok_or_else(|| Error::Runtime("arithmetic overflow".to_string())),
+        Overflow::Wrapping => Ok(wrapping(m, n)),
+    };
+    match op2 {
+        Op2::Div | Op2::Mod if n == 0 => Err(Error::DivByZero {
+            block: None,
+            pos: None,
+        }),
+        Op2::Shl | Op2::Shr if !(0..32).contains(&n) => {
+            let msg = format!("shift by {} is out of range", n);
+            Err(Error::Runtime(msg))
+        }
+        Op2::Pow if n < 0 => {
+            let msg = format!("negative exponent {}", n);
+            Err(Error::Runtime(msg))
+        }
+        Op2::Add => arith(i32::checked_add, i32::wrapping_add),
+        Op2::Sub => arith(i32::checked_sub, i32::wrapping_sub),
+        Op2::Mul => arith(i32::checked_mul, i32::wrapping_mul),
+        // i32::MIN / -1 overflows too.
+        Op2::Div => arith(i32::checked_div, i32::wrapping_div),
+        Op2::Mod => arith(i32::checked_rem, i32::wrapping_rem),
+        Op2::LT => Ok(if m < n { 1 } else { 0 }),
+        Op2::Eq => Ok(if m == n { 1 } else { 0 }),
+        Op2::GT => Ok(if m > n { 1 } else { 0 }),
+        Op2::LE => Ok(if m <= n { 1 } else { 0 }),
+        Op2::GE => Ok(if m >= n { 1 } else { 0 }),
+        Op2::NE => Ok(if m != n { 1 } else { 0 }),
+        Op2::And => Ok(m & n),
+        Op2::Or => Ok(m | n),
+        Op2::Xor => Ok(m ^ n),
+        Op2::Shl => Ok(m << n),
+        // Shifting an i32 right is arithmetic.
+        Op2::Shr => Ok(m >> n),
+        Op2::Min => Ok(m.min(n)),
+        Op2::Max => Ok(m.max(n)),
+        Op2::Pow => arith(
+            |m, n| m.checked_pow(n as u32),
+            |m, n| m.wrapping_pow(n as u32),
+        ),
+    }
+}
+
+pub fn eval_op1(op1: &Op1, overflow: Overflow, n: i32) -> Result<i32, Error> {
+    match (op1, overflow) {
+        // abs(i32::MIN) does not fit.
+        (Op1::Abs, Overflow::Checked) => n
+            .checked_abs()
+            .ok_or_else(|| Error::Runtime("arithmetic overflow".to_string())),
+        (Op1::Abs, Overflow::Wrapping) => Ok(n.wrapping_abs()),
+        (Op1::Sign, _) => Ok(n.signum()),
+    }
+}
+
+// A function that computes `m op2 n` under `overflow`, or returns `None` where
+// `eval_op2` would fail. It agrees with `eval_op2` but skips building a
+// `Result`, which matters in tight loops.
+pub fn fast_op2(op2: &Op2, overflow: Overflow) -> fn(i32, i32) -> Option<i32> {
+    let checked = overflow == Overflow::Checked;
     match op2 {
-        Op2::Add => m + n,
-        Op2::Sub => m - n,
-        Op2::Mul => m * n,
-        Op2::Div => m / n,
-        Op2::Mod => m % n,
-        Op2::LT => if m < n { 1 } else { 0 },
-        Op2::Eq => if m == n { 1 } else { 0 }
+        Op2::Add if checked => i32::checked_add,
+        Op2::Add => |m, n| Some(m.wrapping_add(n)),
+        Op2::Sub if checked => i32::checked_sub,
+        Op2::Sub => |m, n| Some(m.wrapping_sub(n)),
+        Op2::Mul if checked => i32::checked_mul,
+        Op2::Mul => |m, n| Some(m.wrapping_mul(n)),
+        // Division by zero fails either way.
+        Op2::Div if checked => i32::checked_div,
+        Op2::Div => |m, n| if n == 0 { None } else { Some(m.wrapping_div(n)) },
+        Op2::Mod if checked => i32::checked_rem,
+        Op2::Mod => |m, n| if n == 0 { None } else { Some(m.wrapping_rem(n)) },
+        Op2::Pow if checked => |m, n| {
+            if n < 0 {
+                None
+            } else {
+                m.checked_pow(n as u32)
+            }
+        },
+        Op2::Pow => |m, n| {
+            if n < 0 {
+                None
+            } else {
+                Some(m.wrapping_pow(n as u32))
+            }
+        },
+        Op2::LT => |m, n| Some((m < n) as i32),
+        Op2::Eq => |m, n| Some((m == n) as i32),
+        Op2::GT => |m, n| Some((m > n) as i32),
+        Op2::LE => |m, n| Some((m <= n) as i32),
+        Op2::GE => |m, n| Some((m >= n) as i32),
+        Op2::NE => |m, n| Some((m != n) as i32),
+        Op2::And => |m, n| Some(m & n),
+        Op2::Or => |m, n| Some(m | n),
+        Op2::Xor => |m, n| Some(m ^ n),
+        // A negative shift becomes too large a u32.
+        Op2::Shl => |m, n| m.checked_shl(n as u32),
+        Op2::Shr => |m, n| m.checked_shr(n as u32),
+        Op2::Min => |m, n| Some(m.min(n)),
+        Op2::Max => |m, n| Some(m.max(n)),
+    }
+}
+
+// An operand of an `Op2`, resolved so that reading it needs no match. It is
+// `registers[reg] & mask | imm`, where `mask` is all ones for a register and
+// zero for a constant, which is in `imm`.
+#[derive(Clone, Copy)]
+struct Operand {
+    reg: usize,
+    mask: i32,
+    imm: i32,
+}
+
+impl Operand {
+    fn new(v: &Val) -> Self {
+        match *v {
+            Val::Reg(reg) => Operand { reg, mask: -1, imm: 0 },
+            Val::Imm(imm) => Operand { reg: 0, mask: 0, imm },
+        }
+    }
+
+    fn get(&self, registers: &[i32]) -> i32 {
+        registers[self.reg] & self.mask | self.imm
+    }
+}
+
+// An `Op2` instruction after `compile_op2s` has resolved its operands and
+// operator.
+struct CompiledOp2<'a> {
+    dst: usize,
+    a: Operand,
+    b: Operand,
+    op: fn(i32, i32) -> Option<i32>,
+    // For errors, which `op` does not describe.
+    op2: &'a Op2,
+    instr: &'a Instr,
+    rest: &'a Instr,
+}
+
+// Hashes a pointer to an instruction, which is already unique, by mixing its
+// bits rather than running SipHash over it.
+#[derive(Default)]
+struct PtrHasher(u64);
+
+impl Hasher for PtrHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, _bytes: &[u8]) {
+        unreachable!("only pointers are hashed")
+    }
+
+    fn write_usize(&mut self, n: usize) {
+        self.0 = ((n as u64) >> 3).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    }
+}
+
+// Every `Op2` in a program, resolved ahead of time. Each run of them, one
+// after another, is stored together, so that the run can go in one step.
+struct Op2s<'a> {
+    runs: Vec<Vec<CompiledOp2<'a>>>,
+    // The run that each `Op2` is in, and where it is in the run.
+    at: HashMap<*const Instr, (usize, usize), BuildHasherDefault<PtrHasher>>,
+}
+
+// The pre-pass that resolves every `Op2` in `blocks`, so that running one
+// does not match on its operands or its operator.
+fn compile_op2s(blocks: &HashMap<i32, Instr>, overflow: Overflow) -> Op2s<'_> {
+    let mut op2s = Op2s {
+        runs: Vec::new(),
+        at: HashMap::default(),
+    };
+    // A preorder visits the start of each run before the rest of it.
+    for instr in blocks.values().flat_map(|block| block.preorder()) {
+        let key = instr as *const Instr;
+        if !matches!(instr, Instr::Op2(..)) || op2s.at.contains_key(&key) {
+            continue;
+        }
+        let mut run = Vec::new();
+        let mut next = instr;
+        while let Instr::Op2(r, op2, v1, v2, rest) = next {
+            let at = (op2s.runs.len(), run.len());
+            op2s.at.insert(next as *const Instr, at);
+            run.push(CompiledOp2 {
+                dst: *r,
+                a: Operand::new(v1),
+                b: Operand::new(v2),
+                op: fast_op2(op2, overflow),
+                op2,
+                instr: next,
+                rest,
+            });
+            next = rest;
+        }
+        op2s.runs.push(run);
     }
+    op2s
+}
+
+// What a task does after running one instruction: it continues at the given
+// instruction, it exits, or it yields and should resume at the given
+// instruction.
+enum Outcome<'a> {
+    Next(&'a Instr),
+    Exit(i32),
+    Yield(&'a Instr),
 }
 
-type R = Result<i32, Error>;
+type R<'a> = Result<Outcome<'a>, Error>;
+
+// Where a `call` resumes once the callee returns.
+struct Frame<'a> {
+    block: i32,
+    next: &'a Instr,
+}
 
-fn print_printable(st: &mut State, p: &Printable) {
+fn print_printable(
+    st: &mut State,
+    env: &Env,
+    instr: &Instr,
+    p: &Printable,
+) -> Result<(), Error> {
     match p {
-        Printable::Id(s) => println!("{}", s),
-        Printable::Val(v) => println!("{}", eval_val(&st.registers, &v)),
+        Printable::Id(s) => writeln!(st.io.out, "{}", s)?,
+        Printable::Val(v) => {
+            let n = eval_val(&st.registers, v);
+            writeln!(st.io.out, "{}", n)?
+        }
+        Printable::Hex(v) => {
+            // Negative values show their two's complement bits.
+            let n = eval_val(&st.registers, v);
+            writeln!(st.io.out, "{:#x}", n)?
+        }
+        Printable::Seq(v1, v2) => {
+            let base = eval_val(&st.registers, v1);
+            let len = eval_val(&st.registers, v2);
+            if base < 0
+                || len < 0
+                || base as usize + len as usize > st.registers.len()
+            {
+                let msg =
+                    format!("seq({}, {}) invalid register range", base, len);
+                return Err(runtime_error(st, env, instr, msg));
+            }
+            let (base, len) = (base as usize, len as usize);
+            let vals = st.registers[base..base + len].to_vec();
+            writeln!(st.io.out, "{:?}", vals)?
+        }
         Printable::Array(v1, v2) => {
-            let ptr = eval_val(&st.registers, &v1) as usize;
-            let len = eval_val(&st.registers, &v2) as usize;
-            if ptr + len >= st.heap.len() {
-                println!(
-                    "attempted to print invalid address {}",
-                    ptr + len
-                );
-            } else {
-                let idx_list = ptr..(ptr + len);
-                let vals = idx_list;
-                print!("[");
-                for val in vals {
-                    print!("{:?}; ", val);
+            let ptr = eval_val(&st.registers, v1);
+            let len = eval_val(&st.registers, v2);
+            if ptr < 0
+                || len < 0
+                || ptr as usize + len as usize > st.heap.len()
+            {
+                let msg =
+                    format!("array({}, {}) invalid address range", ptr, len);
+                return Err(runtime_error(st, env, instr, msg));
+            }
+            let (ptr, len) = (ptr as usize, len as usize);
+            let mut vals = Vec::with_capacity(len);
+            for addr in ptr..ptr + len {
+                check_live(st, env, instr, addr)?;
+                vals.push(read(st, env, instr, addr)?);
+            }
+            writeln!(st.io.out, "{:?}", vals)?
+        }
+    }
+    Ok(())
+}
+
+// The next whitespace-delimited word of `input`, or "" at the end.
+fn read_word(input: &mut dyn BufRead) -> io::Result<String> {
+    let mut word = Vec::new();
+    loop {
+        let (used, done) = {
+            let buf = input.fill_buf()?;
+            if buf.is_empty() {
+                break;
+            }
+            let mut used = 0;
+            let mut done = false;
+            for b in buf {
+                used += 1;
+                if !b.is_ascii_whitespace() {
+                    word.push(*b);
+                } else if !word.is_empty() {
+                    done = true;
+                    break;
                 }
-                println!("]");
             }
+            (used, done)
+        };
+        input.consume(used);
+        if done {
+            break;
         }
     }
+    Ok(String::from_utf8_lossy(&word).into_owned())
 }
 
-fn malloc(free_list: FreeList, size: usize) -> Option<(FreeList, usize)> {
+// The base and size of the block in `blocks` that contains `ptr`, if any.
+// The blocks must not overlap.
+fn find_block(
+    blocks: &BTreeMap<usize, usize>,
+    ptr: usize,
+) -> Option<(usize, usize)> {
+    blocks
+        .range(..=ptr)
+        .next_back()
+        .filter(|(base, size)| ptr < **base + **size)
+        .map(|(base, size)| (*base, *size))
+}
+
+// Fails if `ptr` is null (0), if it is in a freed block, or, with strict
+// bounds, if it is not in an allocated block.
+fn check_live(
+    st: &State,
+    env: &Env,
+    instr: &Instr,
+    ptr: usize,
+) -> Result<(), Error> {
+    if ptr == 0 {
+        let msg = "null pointer dereference".to_string();
+        return Err(runtime_error(st, env, instr, msg));
+    }
+    if find_block(&st.alloc_blocks, ptr).is_some() {
+        return Ok(());
+    }
+    if find_block(&st.freed_blocks, ptr).is_some() {
+        let msg = format!("use after free at {}", ptr);
+        return Err(runtime_error(st, env, instr, msg));
+    }
+    if env.strict_bounds {
+        let msg = format!("address {} is not in an allocated block", ptr);
+        return Err(runtime_error(st, env, instr, msg));
+    }
+    Ok(())
+}
+
+// Checks that the `n` words starting at `ptr` are in the heap and live, and
+// that they do not run past the end of the block that `ptr` is in, and
+// returns `ptr` as an address. `op` names the instruction in errors.
+fn check_range(
+    st: &State,
+    env: &Env,
+    instr: &Instr,
+    op: &str,
+    ptr: i32,
+    n: usize,
+) -> Result<usize, Error> {
+    let base = ptr as usize;
+    if let Some((block, size)) = find_block(&st.alloc_blocks, base) {
+        if base.saturating_add(n) > block + size {
+            let msg = format!(
+                "{} of {} words at {} overruns the block at {}, which has {} \
+                 words",
+                op, n, ptr, block, size
+            );
+            return Err(runtime_error(st, env, instr, msg));
+        }
+    }
+    for i in 0..n {
+        let addr = base.wrapping_add(i);
+        if addr >= st.heap.len() {
+            return Err(out_of_bounds(st, env, instr, addr));
+        }
+        check_live(st, env, instr, addr)?;
+    }
+    Ok(base)
+}
+
+// The heap address of `a[i]`, where register `a` holds a pointer.
+fn index_address(
+    st: &State,
+    env: &Env,
+    instr: &Instr,
+    a: usize,
+    i: &Val,
+) -> Result<usize, Error> {
+    let base = st.registers[a];
+    let index = eval_val(&st.registers, i);
+    if env.strict_bounds {
+        let size = match st.alloc_blocks.get(&(base as usize)) {
+            Some(size) => *size,
+            None => {
+                let msg =
+                    format!("r{} = {} is not an allocated block", a, base);
+                return Err(runtime_error(st, env, instr, msg));
+            }
+        };
+        if index < 0 || index as usize >= size {
+            let msg = format!(
+                "index {} out of bounds for block of size {}",
+                index, size
+            );
+            return Err(runtime_error(st, env, instr, msg));
+        }
+    }
+    let ptr = (base as usize).wrapping_add(index as usize);
+    if ptr >= st.heap.len() {
+        return Err(out_of_bounds(st, env, instr, ptr));
+    }
+    check_live(st, env, instr, ptr)?;
+    Ok(ptr)
+}
+
+// The block that `v` was derived from, if it is a register holding a pointer.
+fn origin(st: &State, v: &Val) -> Option<usize> {
+    match *v {
+        Val::Imm(_) => None,
+        Val::Reg(r) => st.origins[r],
+    }
+}
+
+// Records where the pointers that `instr` writes come from, before it runs.
+// Allocations record the blocks that they return themselves.
+fn track_origins(st: &mut State, instr: &Instr) {
+    match instr {
+        Instr::Copy(r, v, _) => st.origins[*r] = origin(st, v),
+        Instr::Swap(a, b, _) => st.origins.swap(*a, *b),
+        // A pointer plus or minus an offset points into the same block.
+        Instr::Op2(r, op, v1, v2, _) => {
+            st.origins[*r] = match op {
+                Op2::Add => origin(st, v1).or_else(|| origin(st, v2)),
+                Op2::Sub => origin(st, v1),
+                _ => None,
+            }
+        }
+        Instr::Load(r, v, _) => {
+            let ptr = eval_val(&st.registers, v) as usize;
+            st.origins[*r] = st.heap_origins.get(&ptr).cloned();
+        }
+        Instr::LoadIdx(r, a, i, _) => {
+            let ptr = (st.registers[*a] as usize)
+                .wrapping_add(eval_val(&st.registers, i) as usize);
+            st.origins[*r] = st.heap_origins.get(&ptr).cloned();
+        }
+        Instr::FetchAdd(r, p, _, _) => {
+            let ptr = st.registers[*p] as usize;
+            st.origins[*r] = st.heap_origins.get(&ptr).cloned();
+        }
+        Instr::Store(a, v, _) => {
+            let ptr = eval_val(&st.registers, a) as usize;
+            set_heap_origin(st, ptr, origin(st, v));
+        }
+        Instr::StoreIdx(a, i, v, _) => {
+            let ptr = (st.registers[*a] as usize)
+                .wrapping_add(eval_val(&st.registers, i) as usize);
+            set_heap_origin(st, ptr, origin(st, v));
+        }
+        Instr::Memcpy(v1, v2, v3, _) => {
+            let dst = eval_val(&st.registers, v1) as usize;
+            let src = eval_val(&st.registers, v2) as usize;
+            let n = heap_words(st, v3);
+            let copied = (0..n)
+                .map(|i| st.heap_origins.get(&src.wrapping_add(i)).cloned())
+                .collect::<Vec<_>>();
+            for (i, o) in copied.into_iter().enumerate() {
+                set_heap_origin(st, dst.wrapping_add(i), o);
+            }
+        }
+        Instr::Memset(v1, _, v3, _) => {
+            let ptr = eval_val(&st.registers, v1) as usize;
+            for i in 0..heap_words(st, v3) {
+                st.heap_origins.remove(&ptr.wrapping_add(i));
+            }
+        }
+        Instr::Op1(r, _, _, _) | Instr::Read(r, _) => st.origins[*r] = None,
+        Instr::Ret(v) => st.origins[0] = origin(st, v),
+        _ => (),
+    }
+}
+
+// A count of words in `v`, limited to the size of the heap.
+fn heap_words(st: &State, v: &Val) -> usize {
+    (eval_val(&st.registers, v).max(0) as usize).min(st.heap.len())
+}
+
+fn set_heap_origin(st: &mut State, addr: usize, origin: Option<usize>) {
+    match origin {
+        Some(base) => st.heap_origins.insert(addr, base),
+        None => st.heap_origins.remove(&addr),
+    };
+}
+
+// With strict bounds, fails if `ptr`, the address in `v`, is outside the
+// block that it was derived from, even if it is inside another block.
+fn check_origin(
+    st: &State,
+    env: &Env,
+    instr: &Instr,
+    v: &Val,
+    ptr: usize,
+) -> Result<(), Error> {
+    if !env.strict_bounds {
+        return Ok(());
+    }
+    let base = match origin(st, v) {
+        Some(base) => base,
+        None => return Ok(()),
+    };
+    match st.alloc_blocks.get(&base) {
+        Some(size) if ptr < base || ptr >= base + size => {
+            let msg = format!(
+                "{} = {} is outside the block at {} that it was derived \
+                 from, which has {} words",
+                v, ptr, base, size
+            );
+            Err(runtime_error(st, env, instr, msg))
+        }
+        _ => Ok(()),
+    }
+}
+
+// The total size of the free blocks in `free_list`.
+fn free_words(free_list: &FreeList) -> usize {
+    let mut total = 0;
+    let mut node = free_list;
+    while let FreeList::Node(_, size, rest) = node {
+        total += size;
+        node = rest;
+    }
+    total
+}
+
+// The base of the smallest free block that can hold `size` words.
+fn best_fit(free_list: &FreeList, size: usize) -> Option<usize> {
+    let mut best: Option<(usize, usize)> = None;
+    let mut node = free_list;
+    while let FreeList::Node(base, free_size, rest) = node {
+        if size <= *free_size && best.is_none_or(|(_, b)| *free_size < b) {
+            best = Some((*base, *free_size));
+        }
+        node = rest;
+    }
+    best.map(|(base, _)| base)
+}
+
+fn malloc(
+    free_list: FreeList,
+    size: usize,
+    alloc: Alloc,
+) -> Option<(FreeList, usize)> {
+    let at = match alloc {
+        Alloc::FirstFit => None,
+        Alloc::BestFit => Some(best_fit(&free_list, size)?),
+    };
+    carve(free_list, size, at)
+}
+
+// Takes `size` words from the first free block that can hold them, or from
+// the free block that starts at `at`, if given.
+fn carve(
+    free_list: FreeList,
+    size: usize,
+    at: Option<usize>,
+) -> Option<(FreeList, usize)> {
     match free_list {
         FreeList::Nil => None,
         FreeList::Node(base, free_size, rest) => {
-            if size == free_size {
+            if size > free_size || at.is_some_and(|at| at != base) {
+                carve(*rest, size, at).map(|(rest2, base2)| {
+                    (FreeList::Node(base, free_size, Box::new(rest2)), base2)
+                })
+            } else if size == free_size {
                 Some((*rest, base))
-            } else if size < free_size {
+            } else {
                 Some((
-                    FreeList::Node(base + size, free_size - base, rest),
+                    FreeList::Node(base + size, free_size - size, rest),
                     base,
                 ))
-            } else {
-                match malloc(*rest, size) {
-                    None => None,
-                    Some((rest2, base2)) => Some((
-                        FreeList::Node(base, free_size, Box::new(rest2)),
-                        base2,
-                    )),
-                }
             }
         }
     }
@@ -108,112 +961,906 @@ fn free(free_list: FreeList, ptr: usize, size: usize) -> FreeList {
     }
 }
 
-fn eval_rec(st: &mut State, env: &Env, instr: &Instr) -> R {
-    match instr {
+// The number of words that `instr` allocates, which `v` gives.
+fn alloc_size(
+    st: &State,
+    env: &Env,
+    instr: &Instr,
+    v: &Val,
+) -> Result<usize, Error> {
+    let n = eval_val(&st.registers, v);
+    if n < 0 {
+        let msg = format!("{} with negative size", instr.kind());
+        return Err(runtime_error(st, env, instr, msg));
+    }
+    Ok(n as usize)
+}
+
+// Hands out a block of `n` words, or 0 if `n` is 0.
+fn allocate(
+    st: &mut State,
+    env: &Env,
+    instr: &Instr,
+    n: usize,
+) -> Result<usize, Error> {
+    if n == 0 {
+        return Ok(0);
+    }
+    let available = free_words(&st.free_list);
+    let mut nil_list = FreeList::Nil;
+    std::mem::swap(&mut st.free_list, &mut nil_list);
+    let (free_list2, ptr) = match malloc(nil_list, n, env.alloc) {
+        Some(result) => result,
+        None => {
+            return Err(Error::OutOfMemory {
+                requested: n,
+                available,
+                block: Some(st.block),
+                pos: position(st, env, instr),
+            })
+        }
+    };
+    st.free_list = free_list2;
+    st.alloc_blocks.insert(ptr, n);
+    st.stats.allocated += n;
+    st.stats.peak_allocated = st.stats.peak_allocated.max(st.stats.allocated);
+    st.freed_blocks
+        .retain(|base, size| *base + *size <= ptr || ptr + n <= *base);
+    Ok(ptr)
+}
+
+// Returns the block of `size` words at `ptr`, which is no longer in
+// `alloc_blocks`, to the free list.
+fn release(st: &mut State, ptr: usize, size: usize) {
+    st.stats.allocated -= size;
+    st.freed_blocks.insert(ptr, size);
+    let mut nil_list = FreeList::Nil;
+    std::mem::swap(&mut st.free_list, &mut nil_list);
+    st.free_list = free(nil_list, ptr, size);
+}
+
+fn read(
+    st: &mut State,
+    env: &Env,
+    instr: &Instr,
+    addr: usize,
+) -> Result<i32, Error> {
+    st.stats.heap_accesses += 1;
+    st.heap.read(addr).map_err(|msg| runtime_error(st, env, instr, msg))
+}
+
+fn write(
+    st: &mut State,
+    env: &Env,
+    instr: &Instr,
+    addr: usize,
+    val: i32,
+) -> Result<(), Error> {
+    st.stats.heap_accesses += 1;
+    st.heap
+        .write(addr, val)
+        .map_err(|msg| runtime_error(st, env, instr, msg))
+}
+
+// Counts `instr` as run, failing if that goes past the step limit or the
+// deadline.
+fn count_instr(st: &mut State, env: &Env, instr: &Instr) -> Result<(), Error> {
+    st.stats.instructions += 1;
+    if env
+        .step_limit
+        .is_some_and(|limit| st.stats.instructions > limit)
+    {
+        let msg = "step limit exceeded".to_string();
+        return Err(runtime_error(st, env, instr, msg));
+    }
+    if st.stats.instructions.is_multiple_of(DEADLINE_CHECK_STEPS)
+        && env.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+    {
+        let msg = "timeout exceeded".to_string();
+        return Err(runtime_error(st, env, instr, msg));
+    }
+    Ok(())
+}
+
+// Runs `instr`, the next instruction of the current task, with `calls` as
+// its call stack. With `fuse`, a run of `Op2`s goes all at once.
+fn step_instr<'a>(
+    st: &mut State,
+    env: &Env<'a>,
+    calls: &mut Vec<Frame<'a>>,
+    instr: &'a Instr,
+    fuse: bool,
+) -> R<'a> {
+    count_instr(st, env, instr)?;
+    if let Some(trace) = st.io.trace.as_mut() {
+        writeln!(trace, "block {}: {}", st.block, instr)?;
+    }
+    if env.strict_bounds {
+        track_origins(st, instr);
+    }
+    let next = match instr {
         Instr::Copy(r, v, rest) => {
-            st.registers[*r] = eval_val(&st.registers, &v);
-            eval_rec(st, env, rest)
+            st.registers[*r] = eval_val(&st.registers, v);
+            rest
         }
-        Instr::Op2(r, op, v1, v2, rest) => {
-            let m = eval_val(&st.registers, &v1);
-            let n = eval_val(&st.registers, &v2);
-            st.registers[*r] = eval_op2(&op, m, n);
-            eval_rec(st, env, rest)
+        Instr::Swap(a, b, rest) => {
+            st.registers.swap(*a, *b);
+            rest
+        }
+        Instr::Op2(..) => {
+            // `compile_op2s` has seen every instruction in the program.
+            let (run, start) = env.op2s.at[&(instr as *const Instr)];
+            let mut run = &env.op2s.runs[run][start..];
+            // Nothing looks at the machine between the instructions of a run
+            // unless it is tracing them or tracking pointers.
+            if !fuse || st.io.trace.is_some() || env.strict_bounds {
+                run = &run[..1];
+            }
+            for (i, compiled) in run.iter().enumerate() {
+                if i > 0 {
+                    count_instr(st, env, compiled.instr)?;
+                }
+                let m = compiled.a.get(&st.registers);
+                let n = compiled.b.get(&st.registers);
+                st.registers[compiled.dst] = match (compiled.op)(m, n) {
+                    Some(result) => result,
+                    None => eval_op2(compiled.op2, env.overflow, m, n)
+                        .map_err(|e| locate_error(st, env, compiled.instr, e))?,
+                };
+            }
+            run[run.len() - 1].rest
+        }
+        Instr::Op1(r, op, v, rest) => {
+            let n = eval_val(&st.registers, v);
+            st.registers[*r] = eval_op1(op, env.overflow, n)
+                .map_err(|e| locate_error(st, env, instr, e))?;
+            rest
         }
         Instr::Load(r, v, rest) => {
             let ptr = eval_val(&st.registers, v) as usize;
             if ptr >= st.heap.len() {
-                return Err(Error::Runtime(format!(
-                    "{} = *{:?} invalid address {}",
-                    r, v, ptr
-                )));
+                return Err(out_of_bounds(st, env, instr, ptr));
             }
-            st.registers[*r] = st.heap[ptr];
-            eval_rec(st, env, rest)
+            check_origin(st, env, instr, v, ptr)?;
+            check_live(st, env, instr, ptr)?;
+            st.registers[*r] = read(st, env, instr, ptr)?;
+            rest
         }
-        Instr::Store(r, v, rest) => {
-            let ptr = st.registers[*r] as usize;
+        Instr::Store(a, v, rest) => {
+            let ptr = eval_val(&st.registers, a) as usize;
+            if ptr >= st.heap.len() {
+                return Err(out_of_bounds(st, env, instr, ptr));
+            }
+            check_origin(st, env, instr, a, ptr)?;
+            check_live(st, env, instr, ptr)?;
+            let n = eval_val(&st.registers, v);
+            write(st, env, instr, ptr, n)?;
+            rest
+        }
+        Instr::LoadIdx(r, a, i, rest) => {
+            let ptr = index_address(st, env, instr, *a, i)?;
+            st.registers[*r] = read(st, env, instr, ptr)?;
+            rest
+        }
+        Instr::StoreIdx(a, i, v, rest) => {
+            let ptr = index_address(st, env, instr, *a, i)?;
+            let n = eval_val(&st.registers, v);
+            write(st, env, instr, ptr, n)?;
+            rest
+        }
+        Instr::FetchAdd(r, p, v, rest) => {
+            let ptr = st.registers[*p] as usize;
             if ptr >= st.heap.len() {
-                return Err(Error::Runtime(format!(
-                    "*{} = {:?} invalid address {}",
-                    r, v, ptr
-                )));
+                return Err(out_of_bounds(st, env, instr, ptr));
             }
-            st.heap[ptr] = eval_val(&st.registers, v);
-            eval_rec(st, env, rest)
+            check_origin(st, env, instr, &Val::Reg(*p), ptr)?;
+            check_live(st, env, instr, ptr)?;
+            let old = read(st, env, instr, ptr)?;
+            let delta = eval_val(&st.registers, v);
+            let new = eval_op2(&Op2::Add, env.overflow, old, delta)
+                .map_err(|e| locate_error(st, env, instr, e))?;
+            write(st, env, instr, ptr, new)?;
+            st.registers[*r] = old;
+            rest
         }
         Instr::Goto(v) => {
             let code_ptr = eval_val(&st.registers, v);
-            match env.instructions.get(&code_ptr) {
-                Option::Some(instr) => eval_rec(st, env, instr),
-                Option::None => Err(Error::Runtime(format!(
-                    "goto({}) invalid code address",
-                    code_ptr
-                ))),
+            match env.targets.get(code_ptr) {
+                Option::Some(block) => {
+                    st.block = code_ptr;
+                    block
+                }
+                Option::None => {
+                    return Err(undefined_block(st, env, instr, code_ptr));
+                }
+            }
+        }
+        Instr::Call(v, rest) => {
+            let code_ptr = eval_val(&st.registers, v);
+            match env.targets.get(code_ptr) {
+                Option::Some(block) => {
+                    calls.push(Frame {
+                        block: st.block,
+                        next: rest,
+                    });
+                    st.block = code_ptr;
+                    block
+                }
+                Option::None => {
+                    return Err(undefined_block(st, env, instr, code_ptr));
+                }
+            }
+        }
+        Instr::Ret(v) => {
+            let n = eval_val(&st.registers, v);
+            match calls.pop() {
+                Some(frame) => {
+                    st.registers[0] = n;
+                    st.block = frame.block;
+                    frame.next
+                }
+                None => {
+                    let msg = "ret with an empty call stack".to_string();
+                    return Err(runtime_error(st, env, instr, msg));
+                }
             }
         }
         Instr::Print(p, rest) => {
-            print_printable(st, p);
-            eval_rec(st, env, rest)
+            print_printable(st, env, instr, p)?;
+            rest
+        }
+        Instr::Tag(name, v, rest) => {
+            let n = eval_val(&st.registers, v);
+            st.records.tags.insert(name.clone(), n);
+            rest
+        }
+        Instr::Capture(name, v1, v2, rest) => {
+            let base = eval_val(&st.registers, v1);
+            let len = eval_val(&st.registers, v2);
+            if base < 0
+                || len < 0
+                || base as usize + len as usize > st.heap.len()
+            {
+                let msg = format!(
+                    "capture({}, {}) invalid address range",
+                    base, len
+                );
+                return Err(runtime_error(st, env, instr, msg));
+            }
+            let (base, len) = (base as usize, len as usize);
+            let mut cells = Vec::with_capacity(len);
+            for addr in base..base + len {
+                cells.push(read(st, env, instr, addr)?);
+            }
+            st.records.captures.push((name.clone(), cells));
+            rest
+        }
+        Instr::Memcpy(v1, v2, v3, rest) => {
+            let dst = eval_val(&st.registers, v1);
+            let src = eval_val(&st.registers, v2);
+            let n = eval_val(&st.registers, v3);
+            if n < 0 {
+                let msg = format!("memcpy of {} words", n);
+                return Err(runtime_error(st, env, instr, msg));
+            }
+            let n = n as usize;
+            if n > 0 {
+                check_origin(st, env, instr, v2, src as usize)?;
+                check_origin(st, env, instr, v1, dst as usize)?;
+            }
+            let src = check_range(st, env, instr, "memcpy", src, n)?;
+            let dst = check_range(st, env, instr, "memcpy", dst, n)?;
+            // Read everything first, in case the ranges overlap.
+            let mut words = Vec::with_capacity(n);
+            for addr in src..src + n {
+                words.push(read(st, env, instr, addr)?);
+            }
+            for (i, word) in words.into_iter().enumerate() {
+                write(st, env, instr, dst + i, word)?;
+            }
+            rest
+        }
+        Instr::Memset(v1, v2, v3, rest) => {
+            let ptr = eval_val(&st.registers, v1);
+            let val = eval_val(&st.registers, v2);
+            let n = eval_val(&st.registers, v3);
+            if n < 0 {
+                let msg = format!("memset of {} words", n);
+                return Err(runtime_error(st, env, instr, msg));
+            }
+            let n = n as usize;
+            if n > 0 {
+                check_origin(st, env, instr, v1, ptr as usize)?;
+            }
+            let ptr = check_range(st, env, instr, "memset", ptr, n)?;
+            for addr in ptr..ptr + n {
+                write(st, env, instr, addr, val)?;
+            }
+            rest
+        }
+        Instr::Assert(v, rest) => {
+            if eval_val(&st.registers, v) == 0 {
+                let msg = "assertion failed".to_string();
+                return Err(runtime_error(st, env, instr, msg));
+            }
+            rest
+        }
+        Instr::Spawn(v, rest) => {
+            let id = eval_val(&st.registers, v);
+            if !env.coroutines {
+                let msg = "spawn requires --coroutines".to_string();
+                return Err(runtime_error(st, env, instr, msg));
+            }
+            if !env.instructions.contains_key(&id) {
+                return Err(undefined_block(st, env, instr, id));
+            }
+            st.spawned.push(id);
+            rest
+        }
+        Instr::Read(r, rest) => {
+            let word = read_word(st.io.input)?;
+            if word.is_empty() {
+                let msg = "read() reached the end of the input".to_string();
+                return Err(runtime_error(st, env, instr, msg));
+            }
+            st.registers[*r] = word.parse::<i32>().map_err(|_| {
+                let msg = format!("read() got {}, not an integer", word);
+                runtime_error(st, env, instr, msg)
+            })?;
+            rest
+        }
+        Instr::Yield(rest) => {
+            if env.coroutines {
+                return Result::Ok(Outcome::Yield(rest));
+            }
+            rest
+        }
+        Instr::Exit(v) => {
+            return Result::Ok(Outcome::Exit(eval_val(&st.registers, v)))
+        }
+        Instr::Abort(reason) => {
+            return Result::Err(Error::Abort {
+                reason: reason.clone(),
+                block: Some(st.block),
+                pos: position(st, env, instr),
+            });
         }
-        Instr::Exit(v) => Result::Ok(eval_val(&st.registers, v)),
-        Instr::Abort() => Result::Err(Error::Runtime("called abort".to_string())),
         Instr::IfZ(v, true_part, false_part) => {
             if eval_val(&st.registers, v) == 0 {
-                eval_rec(st, env, true_part)
+                true_part
             } else {
-                eval_rec(st, env, false_part)
+                false_part
             }
         }
         Instr::Malloc(r, v, rest) => {
-            let n = eval_val(&st.registers, v) as usize;
-            if n == 0 {
-                st.registers[*r] = 0;
-            }
-            else {
-                let mut nil_list = FreeList::Nil;
-                std::mem::swap(&mut st.free_list, &mut nil_list);
-                let (free_list2, ptr) = try!(
-                    malloc(nil_list, n)
-                        .ok_or(Error::Runtime("malloc OOM".to_string()))
-                );
-                st.free_list = free_list2;
-                st.registers[*r] = ptr as i32;
-                st.alloc_blocks.insert(ptr, n);
+            let n = alloc_size(st, env, instr, v)?;
+            st.stats.mallocs += 1;
+            let ptr = allocate(st, env, instr, n)?;
+            if env.poison {
+                for addr in ptr..ptr + n {
+                    write(st, env, instr, addr, POISON)?;
+                }
+            }
+            st.registers[*r] = ptr as i32;
+            st.origins[*r] = Some(ptr);
+            rest
+        }
+        Instr::Calloc(r, v, rest) => {
+            let n = alloc_size(st, env, instr, v)?;
+            st.stats.mallocs += 1;
+            let ptr = allocate(st, env, instr, n)?;
+            for addr in ptr..ptr + n {
+                write(st, env, instr, addr, 0)?;
             }
-            eval_rec(st, env, rest)
+            st.registers[*r] = ptr as i32;
+            st.origins[*r] = Some(ptr);
+            rest
+        }
+        Instr::Realloc(r, old, v, rest) => {
+            let old_ptr = st.registers[*old] as usize;
+            let old_size = match st.alloc_blocks.get(&old_ptr) {
+                Some(size) => *size,
+                None => {
+                    let msg = format!(
+                        "realloc of {}, which is not an allocated block",
+                        old_ptr
+                    );
+                    return Err(runtime_error(st, env, instr, msg));
+                }
+            };
+            let n = alloc_size(st, env, instr, v)?;
+            let ptr = allocate(st, env, instr, n)?;
+            for i in 0..old_size.min(n) {
+                let word = read(st, env, instr, old_ptr + i)?;
+                write(st, env, instr, ptr + i, word)?;
+            }
+            for i in 0..old_size.min(n) {
+                let o = st.heap_origins.remove(&(old_ptr + i));
+                set_heap_origin(st, ptr + i, o);
+            }
+            st.alloc_blocks.remove(&old_ptr);
+            release(st, old_ptr, old_size);
+            st.registers[*r] = ptr as i32;
+            st.origins[*r] = Some(ptr);
+            rest
         }
         Instr::Free(r, rest) => {
+            st.stats.frees += 1;
             let ptr = st.registers[*r] as usize;
-            let mut nil_list = FreeList::Nil;
-            let size = *try!(
-                st.alloc_blocks
-                    .get(&ptr)
-                    .ok_or(Error::Runtime("free bad ptr".to_string()))
+            let size = match st.alloc_blocks.remove(&ptr) {
+                Some(size) => size,
+                None if st.freed_blocks.contains_key(&ptr) => {
+                    return Err(Error::DoubleFree {
+                        addr: ptr,
+                        block: Some(st.block),
+                        pos: position(st, env, instr),
+                    });
+                }
+                None => {
+                    let msg = match find_block(&st.alloc_blocks, ptr) {
+                        Some((base, _)) => format!(
+                            "free of {}, which is inside the block at {} \
+                             but is not its base",
+                            ptr, base
+                        ),
+                        None => format!(
+                            "free of {}, which was never allocated",
+                            ptr
+                        ),
+                    };
+                    return Err(runtime_error(st, env, instr, msg));
+                }
+            };
+            release(st, ptr, size);
+            rest
+        }
+    };
+    Ok(Outcome::Next(next))
+}
+
+// A task, which has its own registers, block, and call stack. Tasks share
+// everything else in `State`.
+struct Task<'a> {
+    main: bool,
+    registers: Vec<i32>,
+    origins: Vec<Option<usize>>,
+    block: i32,
+    next: &'a Instr,
+    calls: Vec<Frame<'a>>,
+}
+
+// Whether the program is still running after a step.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StepResult {
+    Running,
+    // Stopped at a breakpoint at the start of this block, before running its
+    // first instruction.
+    Paused(i32),
+    Exited(i32),
+}
+
+// A program that runs one instruction at a time. Tasks run round-robin,
+// starting with the main task at `config.start`, until the main task exits.
+// Tasks that are still running at that point are abandoned.
+pub struct Machine<'a, 'io> {
+    st: State<'io>,
+    env: Env<'a>,
+    // The running task, whose registers and block are in `st` instead.
+    task: Task<'a>,
+    // Tasks waiting for their turn.
+    queue: VecDeque<Task<'a>>,
+    // The first instruction of every block with a breakpoint.
+    breakpoints: Vec<(i32, &'a Instr)>,
+    // Whether the last step paused at a breakpoint, so that the next one
+    // moves past it.
+    at_break: bool,
+    exit_code: Option<i32>,
+}
+
+impl<'a, 'io> Machine<'a, 'io> {
+    // Prepares to run `blocks` from `config.start`. Runtime errors mention the
+    // source position of the failing instruction when `positions` has it.
+    // Every load and store goes through `heap`, whose length takes the place
+    // of `config.heap_size`, and input and output go through `io`. Programs
+    // that use more than `config.num_registers` registers are rejected, and
+    // so is a heap with no room to allocate past address 0.
+    pub fn new(
+        config: &Config,
+        blocks: &'a HashMap<i32, Instr>,
+        positions: &'a SourceMap,
+        heap: Box<dyn Memory>,
+        io: Io<'io>,
+    ) -> Result<Self, Error> {
+        tc::check_registers(blocks, config.num_registers)?;
+        let heap_size = heap.len();
+        if heap_size < MIN_HEAP_SIZE {
+            let msg = format!(
+                "a heap of {} words is too small, since address 0 is never \
+                 allocated",
+                heap_size
             );
+            return Err(Error::Usage(msg));
+        }
+        let mut st = State {
+            heap,
+            registers: vec![0; config.num_registers],
+            origins: vec![None; config.num_registers],
+            free_list: FreeList::Node(
+                1,
+                heap_size - 1,
+                Box::new(FreeList::Nil),
+            ),
+            alloc_blocks: BTreeMap::new(),
+            freed_blocks: BTreeMap::new(),
+            heap_origins: HashMap::new(),
+            records: Records::default(),
+            block: config.start,
+            spawned: Vec::new(),
+            stats: Stats::default(),
+            io,
+        };
+        if !config.args.is_empty() {
+            if config.num_registers < 2 {
+                let msg = "passing args requires at least 2 registers";
+                return Err(Error::Usage(msg.to_string()));
+            }
+            let n = config.args.len();
+            let mut nil_list = FreeList::Nil;
             std::mem::swap(&mut st.free_list, &mut nil_list);
-            st.free_list = free(nil_list, ptr, size);
-            eval_rec(st, env, rest)
+            let (free_list2, ptr) = malloc(nil_list, n, config.alloc)
+                .ok_or_else(|| {
+                    Error::Usage(format!("not enough memory for {} args", n))
+                })?;
+            st.free_list = free_list2;
+            st.alloc_blocks.insert(ptr, n);
+            st.stats.allocated = n;
+            st.stats.peak_allocated = n;
+            for (i, arg) in config.args.iter().enumerate() {
+                st.heap.write(ptr + i, *arg).map_err(Error::Runtime)?;
+            }
+            st.registers[0] = n as i32;
+            st.registers[1] = ptr as i32;
+            st.origins[1] = Some(ptr);
+        }
+        let entry = blocks.get(&config.start).ok_or_else(|| {
+            Error::Usage(format!("Expected block {}", config.start))
+        })?;
+        let breakpoints = config
+            .breakpoints
+            .iter()
+            .map(|id| match blocks.get(id) {
+                Some(start) => Ok((*id, start)),
+                None => Err(Error::Usage(format!(
+                    "breakpoint on block {}, which does not exist",
+                    id
+                ))),
+            }).collect::<Result<_, Error>>()?;
+        let env = Env {
+            instructions: blocks,
+            op2s: compile_op2s(blocks, config.overflow),
+            targets: Targets::new(blocks),
+            positions,
+            coroutines: config.coroutines,
+            strict_bounds: config.strict_bounds,
+            step_limit: config.step_limit,
+            deadline: config
+                .timeout
+                .filter(|_| !config.deterministic)
+                .map(|timeout| Instant::now() + timeout),
+            overflow: config.overflow,
+            alloc: config.alloc,
+            poison: config.poison,
+        };
+        Ok(Machine {
+            st,
+            env,
+            task: Task {
+                main: true,
+                registers: Vec::new(),
+                origins: Vec::new(),
+                block: config.start,
+                next: entry,
+                calls: Vec::new(),
+            },
+            queue: VecDeque::new(),
+            breakpoints,
+            at_break: false,
+            exit_code: None,
+        })
+    }
+
+    // Runs the next instruction, unless the program has already exited.
+    pub fn step(&mut self) -> Result<StepResult, Error> {
+        self.advance(false)
+    }
+
+    // Like `step`, but runs a whole run of `Op2`s at once.
+    fn advance(&mut self, fuse: bool) -> Result<StepResult, Error> {
+        if let Some(n) = self.exit_code {
+            return Ok(StepResult::Exited(n));
+        }
+        if !self.at_break {
+            let next = self.task.next;
+            let hit = self
+                .breakpoints
+                .iter()
+                .find(|(_, start)| std::ptr::eq(*start, next));
+            if let Some((id, _)) = hit {
+                self.at_break = true;
+                return Ok(StepResult::Paused(*id));
+            }
+        }
+        self.at_break = false;
+        let outcome = step_instr(
+            &mut self.st,
+            &self.env,
+            &mut self.task.calls,
+            self.task.next,
+            fuse,
+        )?;
+        let instructions = self.env.instructions;
+        for id in self.st.spawned.drain(..) {
+            self.queue.push_back(Task {
+                main: false,
+                registers: vec![0; self.st.registers.len()],
+                origins: vec![None; self.st.registers.len()],
+                block: id,
+                next: &instructions[&id],
+                calls: Vec::new(),
+            });
+        }
+        match outcome {
+            Outcome::Next(next) => self.task.next = next,
+            Outcome::Exit(n) if self.task.main => {
+                self.exit_code = Some(n);
+                return Ok(StepResult::Exited(n));
+            }
+            Outcome::Exit(_) => self.switch_task(false),
+            Outcome::Yield(next) => {
+                self.task.next = next;
+                self.switch_task(true);
+            }
+        }
+        Ok(StepResult::Running)
+    }
+
+    // Runs the program until it exits, returning its exit value. It does not
+    // stop at breakpoints.
+    pub fn run(&mut self) -> Result<i32, Error> {
+        loop {
+            if let StepResult::Exited(n) = self.advance(true)? {
+                return Ok(n);
+            }
+        }
+    }
+
+    // Reports a pause at the breakpoint on block `id` in the trace, or on
+    // stderr if there is no trace.
+    fn report_breakpoint(&mut self, id: i32) -> io::Result<()> {
+        match self.st.io.trace.as_mut() {
+            Some(trace) => writeln!(trace, "breakpoint: block {}", id),
+            None => writeln!(io::stderr(), "breakpoint: block {}", id),
+        }
+    }
+
+    // Moves on to the task at the front of the queue. The running task goes
+    // to the back if `keep` is set.
+    fn switch_task(&mut self, keep: bool) {
+        if keep {
+            let task = Task {
+                main: self.task.main,
+                registers: self.st.registers.clone(),
+                origins: self.st.origins.clone(),
+                block: self.st.block,
+                next: self.task.next,
+                calls: std::mem::take(&mut self.task.calls),
+            };
+            self.queue.push_back(task);
+        }
+        let task =
+            self.queue.pop_front().expect("the main task is always queued");
+        self.st.registers = task.registers;
+        self.st.origins = task.origins;
+        self.st.block = task.block;
+        self.task = Task {
+            registers: Vec::new(),
+            origins: Vec::new(),
+            ..task
+        };
+    }
+
+    // The registers of the running task.
+    pub fn registers(&self) -> &[i32] {
+        &self.st.registers
+    }
+
+    // The block that the running task most recently entered.
+    pub fn block(&self) -> i32 {
+        self.st.block
+    }
+
+    // The instruction that the next step runs, or `None` once the program
+    // has exited.
+    pub fn next_instr(&self) -> Option<&'a Instr> {
+        match self.exit_code {
+            Some(_) => None,
+            None => Some(self.task.next),
         }
     }
+
+    // The base address and contents of every allocated block, in address
+    // order.
+    pub fn allocated_blocks(&mut self) -> Vec<(usize, Vec<Option<i32>>)> {
+        let heap = &mut self.st.heap;
+        self.st
+            .alloc_blocks
+            .iter()
+            .map(|(base, size)| {
+                let vals = (*base..*base + *size)
+                    .map(|addr| heap.read(addr).ok())
+                    .collect();
+                (*base, vals)
+            }).collect()
+    }
+
+    // Writes the registers and allocated blocks to the dump writer in `io`,
+    // if there is one.
+    pub fn dump(&mut self) -> io::Result<()> {
+        match self.st.io.dump.take() {
+            Some(out) => {
+                let r = dump_state(self, out);
+                self.st.io.dump = Some(out);
+                r
+            }
+            None => Ok(()),
+        }
+    }
+
+    // The machine as the program left it, once it has exited.
+    pub fn finish(mut self) -> Result<FinalState, Error> {
+        let exit_code = self.exit_code.ok_or_else(|| {
+            Error::Usage("the program has not exited".to_string())
+        })?;
+        let st = &mut self.st;
+        let heap = (0..st.heap.used_len())
+            .map(|addr| st.heap.read(addr).map_err(Error::Runtime))
+            .collect::<Result<Vec<i32>, Error>>()?;
+        Ok(FinalState {
+            exit_code,
+            registers: self.st.registers,
+            heap,
+            records: self.st.records,
+            stats: self.st.stats,
+        })
+    }
 }
 
+// The machine as the program left it when it exited.
+pub struct FinalState {
+    pub exit_code: i32,
+    pub registers: Vec<i32>,
+    // The heap, up to the last word that the program may have written. The
+    // words after that are 0.
+    pub heap: Vec<i32>,
+    pub records: Records,
+    pub stats: Stats,
+}
+
+// Writes the registers of the running task and the contents of every
+// allocated block to `out`.
+fn dump_state(machine: &mut Machine, out: &mut dyn Write) -> io::Result<()> {
+    writeln!(out, "registers:")?;
+    for (r, val) in machine.registers().iter().enumerate() {
+        writeln!(out, "    r{} = {}", r, val)?;
+    }
+    writeln!(out, "allocated blocks:")?;
+    for (base, vals) in machine.allocated_blocks() {
+        let vals: Vec<String> = vals
+            .into_iter()
+            .map(|val| match val {
+                Some(val) => val.to_string(),
+                None => "?".to_string(),
+            }).collect();
+        writeln!(out, "    {}: [{}]", base, vals.join(", "))?;
+    }
+    Ok(())
+}
+
+// Runs the program from `config.start`, returning its exit value.
 pub fn eval(
-    heap_size: usize,
-    num_registers: usize,
+    config: &Config,
+    blocks: HashMap<i32, Instr>,
+    positions: SourceMap,
+) -> Result<i32, Error> {
+    eval_with_state(config, blocks, positions).map(|st| st.exit_code)
+}
+
+// Like `eval`, but runs the program from block `start` instead of
+// `config.start`.
+pub fn eval_from(
+    start: i32,
+    config: &Config,
+    blocks: HashMap<i32, Instr>,
+    positions: SourceMap,
+) -> Result<i32, Error> {
+    let config = Config {
+        start,
+        ..config.clone()
+    };
+    eval(&config, blocks, positions)
+}
+
+// Runs the program from `config.start`, returning its exit value and what it
+// recorded.
+pub fn eval_with_records(
+    config: &Config,
+    blocks: HashMap<i32, Instr>,
+    positions: SourceMap,
+) -> Result<(i32, Records), Error> {
+    eval_with_state(config, blocks, positions)
+        .map(|st| (st.exit_code, st.records))
+}
+
+// Runs the program from `config.start`, returning the final state of the
+// machine.
+// Runtime errors mention the source position of the failing instruction when
+// `positions` has it.
+pub fn eval_with_state(
+    config: &Config,
+    blocks: HashMap<i32, Instr>,
+    positions: SourceMap,
+) -> Result<FinalState, Error> {
+    let stdin = io::stdin();
+    let mut stdout = io::stdout();
+    eval_with_io(config, blocks, positions, &mut stdin.lock(), &mut stdout)
+}
+
+// Like `eval_with_state`, but `read` takes integers from `input` and `print`
+// writes to `out`, instead of stdin and stdout.
+pub fn eval_with_io(
+    config: &Config,
     blocks: HashMap<i32, Instr>,
-) -> R {
-    let mut st = State {
-        heap: vec![0; heap_size],
-        registers: vec![0; num_registers],
-        free_list: FreeList::Node(1, heap_size - 1, Box::new(FreeList::Nil)),
-        alloc_blocks: HashMap::new(),
+    positions: SourceMap,
+    input: &mut dyn BufRead,
+    out: &mut dyn Write,
+) -> Result<FinalState, Error> {
+    let heap = Box::new(LazyHeap::new(config.heap_size));
+    let mut stderr = io::stderr();
+    let mut dump = io::stderr();
+    let io = Io {
+        input,
+        out,
+        trace: if config.trace { Some(&mut stderr) } else { None },
+        dump: if config.dump_on_error { Some(&mut dump) } else { None },
     };
-    let env = Env {
-        instructions: blocks,
+    eval_with_memory(config, blocks, positions, heap, io)
+}
+
+// Like `eval_with_state`, but every load and store goes through `heap`,
+// whose length takes the place of `config.heap_size`, and input and output go
+// through `io` instead of stdin, stdout, and stderr.
+pub fn eval_with_memory(
+    config: &Config,
+    blocks: HashMap<i32, Instr>,
+    positions: SourceMap,
+    heap: Box<dyn Memory>,
+    io: Io,
+) -> Result<FinalState, Error> {
+    let mut machine = Machine::new(config, &blocks, &positions, heap, io)?;
+    let r = loop {
+        match machine.advance(true) {
+            Ok(StepResult::Running) => (),
+            Ok(StepResult::Paused(id)) => machine.report_breakpoint(id)?,
+            Ok(StepResult::Exited(n)) => break Ok(n),
+            Err(err) => break Err(err),
+        }
     };
-    env.instructions
-        .get(&0)
-        .ok_or(Error::Usage("Expected block 0".to_string()))
-        .and_then(|instr| eval_rec(&mut st, &env, instr))
+    match r {
+        Ok(_) => machine.finish(),
+        Err(err) => {
+            // The program's error matters more than one writing the dump.
+            let _ = machine.dump();
+            Err(err)
+        }
+    }
 }
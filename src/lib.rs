@@ -0,0 +1,33 @@
+#![recursion_limit = "128"]
+
+#[macro_use]
+extern crate combine;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+
+pub mod error;
+pub mod eval;
+pub mod opt;
+pub mod parser;
+pub mod syntax;
+pub mod tc;
+
+use error::Error;
+
+// Parses, checks, and runs `code` with a heap of `mem_limit` words and
+// `reg_limit` registers, returning the value that the program exits with.
+pub fn run(
+    code: &str,
+    mem_limit: usize,
+    reg_limit: usize,
+) -> Result<i32, Error> {
+    let (blocks, positions) = parser::parse_with_positions(code)?;
+    let blocks = tc::tc(blocks)?;
+    let config = eval::Config {
+        heap_size: mem_limit,
+        num_registers: reg_limit,
+        ..Default::default()
+    };
+    eval::eval(&config, blocks, positions)
+}
@@ -1,33 +1,516 @@
-#![recursion_limit = "128"]
-
-#[macro_use]
-extern crate combine;
 extern crate clap;
+extern crate ilvm;
+#[cfg(feature = "serde")]
+extern crate serde_json;
 
-mod error;
-mod eval;
-mod parser;
-mod syntax;
-mod tc;
-
-use clap::{App, Arg};
-use error::*;
+use clap::{App, Arg, ArgMatches};
+use ilvm::error::*;
+use ilvm::{eval, opt, parser, syntax, tc};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
+use std::io;
 use std::io::prelude::*;
+use std::path::Path;
 use std::process;
+use std::time::Duration;
 
-fn parse_and_eval(
-    code: &str,
-    mem_limit: usize,
-    reg_limit: usize,
+// Settings from the command line.
+#[derive(Default)]
+struct Options {
+    config: eval::Config,
+    warn_infinite: bool,
+    warn_bad_store: bool,
+    warn_unreachable: bool,
+    check_init: bool,
+    optimize: bool,
+    dump_tags: bool,
+    dump_captures: bool,
+    verbose: bool,
+    deny: Vec<String>,
+}
+
+type Program = (Vec<syntax::Block>, syntax::SourceMap, parser::Settings);
+
+// Parses a program split across `files`, given as (name, source) pairs.
+// Errors name the file they are in, if there is more than one.
+fn parse_files(files: &[(&str, &str)]) -> Result<Program, Error> {
+    let sources: Vec<&str> = files.iter().map(|(_, source)| *source).collect();
+    let in_file = |(i, err): (usize, Error)| match err {
+        Error::Parse(msg) if files.len() > 1 => {
+            Error::Parse(format!("{}: {}", files[i].0, msg))
+        }
+        Error::Usage(msg) if files.len() > 1 => {
+            Error::Usage(format!("{}: {}", files[i].0, msg))
+        }
+        err => err,
+    };
+    let mut defined_in: HashMap<i32, &str> = HashMap::new();
+    let mut blocks = Vec::new();
+    let mut positions = syntax::SourceMap::new();
+    let (parsed, settings) =
+        parser::parse_sources(&sources).map_err(in_file)?;
+    for (&(name, _), (file_blocks, file_positions)) in files.iter().zip(parsed)
+    {
+        for (id, _) in &file_blocks {
+            match defined_in.insert(*id, name) {
+                Some(other) if other != name => {
+                    return Err(Error::Usage(format!(
+                        "block {} is defined in both {} and {}",
+                        id, other, name
+                    )))
+                }
+                _ => (),
+            }
+        }
+        blocks.extend(file_blocks);
+        positions.extend(file_positions);
+    }
+    Ok((blocks, positions, settings))
+}
+
+// Uses the program's directives for the settings that were not given on the
+// command line.
+fn apply_settings(
+    config: &mut eval::Config,
+    settings: &parser::Settings,
+    args: &ArgMatches,
+) {
+    if args.occurrences_of("memlimit") == 0 {
+        if let Some(words) = settings.memory {
+            config.heap_size = words;
+        }
+    }
+    if args.occurrences_of("reglimit") == 0 {
+        if let Some(n) = settings.registers {
+            config.num_registers = n;
+        }
+    }
+}
+
+// Runs the checks and prints the warnings that `opts` asks for.
+fn check_program(
+    blocks: &HashMap<i32, syntax::Instr>,
+    opts: &Options,
+) -> Result<(), Error> {
+    tc::deny(blocks, &opts.deny)?;
+    tc::check_registers(blocks, opts.config.num_registers)?;
+    // With --arg, r0 and r1 start out holding the arguments.
+    let arg_regs: HashSet<usize> = if opts.config.args.is_empty() {
+        HashSet::new()
+    } else {
+        vec![0, 1].into_iter().collect()
+    };
+    if opts.check_init {
+        tc::check_initialized(blocks, arg_regs.clone())?;
+    }
+    if opts.warn_infinite {
+        for cycle in tc::infinite_loops(blocks) {
+            let ids: Vec<String> =
+                cycle.iter().map(|n| n.to_string()).collect();
+            eprintln!(
+                "warning: block(s) {} loop forever with no exit",
+                ids.join(", ")
+            );
+        }
+    }
+    if opts.warn_unreachable {
+        let ids: Vec<String> = tc::unreachable_blocks(blocks)
+            .iter()
+            .map(|n| n.to_string())
+            .collect();
+        if !ids.is_empty() {
+            eprintln!("warning: block(s) {} can never run", ids.join(", "));
+        }
+    }
+    if opts.warn_bad_store {
+        // With --arg, r1 starts out pointing to the arguments.
+        let entry = arg_regs.into_iter().filter(|r| *r == 1).collect();
+        for (id, r) in tc::bad_stores(blocks, entry) {
+            eprintln!(
+                "warning: block {}: store through possibly-non-pointer \
+                 register r{}",
+                id, r
+            );
+        }
+    }
+    Ok(())
+}
+
+// Checks the program without running it, reporting the highest register it
+// uses.
+fn check_blocks(
+    blocks: Vec<syntax::Block>,
+    opts: &Options,
+) -> Result<(), Error> {
+    let blocks = tc::tc(blocks)?;
+    match tc::highest_register(&blocks) {
+        Some(r) => println!("highest register: r{}", r),
+        None => println!("highest register: none"),
+    }
+    check_program(&blocks, opts)?;
+    println!("fits in {} registers", opts.config.num_registers);
+    Ok(())
+}
+
+// Checks and runs the program, with `read` taking integers from `input` and
+// `print` writing to `out`.
+fn check_and_eval(
+    blocks: Vec<syntax::Block>,
+    positions: syntax::SourceMap,
+    opts: &Options,
+    input: &mut dyn BufRead,
+    out: &mut dyn Write,
 ) -> Result<i32, Error> {
-    let blocks = try!(parser::parse(code));
-    let blocks = try!(tc::tc(blocks));
-    return eval::eval(mem_limit, reg_limit, blocks);
+    let blocks = tc::tc(blocks)?;
+    check_program(&blocks, opts)?;
+    let (blocks, positions) = if opts.optimize {
+        opt::optimize(blocks, positions)
+    } else {
+        (blocks, positions)
+    };
+    let st =
+        eval::eval_with_io(&opts.config, blocks, positions, input, out)?;
+    if opts.dump_tags {
+        print!("{}", format_tags(&st.records.tags));
+    }
+    if opts.dump_captures {
+        print!("{}", format_captures(&st.records.captures));
+    }
+    if opts.verbose {
+        eprint!("{}", format_stats(&st.stats));
+    }
+    Ok(st.exit_code)
+}
+
+// A summary of `stats`, one count per line.
+fn format_stats(stats: &eval::Stats) -> String {
+    format!(
+        "instructions: {}\n\
+         malloc calls: {}\n\
+         free calls: {}\n\
+         words allocated: {} (peak {})\n\
+         heap accesses: {}\n",
+        stats.instructions,
+        stats.mallocs,
+        stats.frees,
+        stats.allocated,
+        stats.peak_allocated,
+        stats.heap_accesses
+    )
+}
+
+// `blocks` as JSON, for --emit-json.
+#[cfg(feature = "serde")]
+fn blocks_json(blocks: &[syntax::Block]) -> Result<String, Error> {
+    serde_json::to_string_pretty(blocks)
+        .map_err(|err| Error::Usage(err.to_string()))
+}
+
+#[cfg(not(feature = "serde"))]
+fn blocks_json(_blocks: &[syntax::Block]) -> Result<String, Error> {
+    let msg = "--emit-json requires ilvm built with the serde feature";
+    Err(Error::Usage(msg.to_string()))
+}
+
+// One `name = value` line per tag, sorted by name.
+fn format_tags(tags: &HashMap<String, i32>) -> String {
+    let mut names: Vec<&String> = tags.keys().collect();
+    names.sort();
+    names
+        .into_iter()
+        .map(|name| format!("{} = {}\n", name, tags[name]))
+        .collect()
+}
+
+// One `name = [...]` line per capture, in the order they were taken.
+fn format_captures(captures: &[(String, Vec<i32>)]) -> String {
+    captures
+        .iter()
+        .map(|(name, cells)| format!("{} = {:?}\n", name, cells))
+        .collect()
+}
+
+// The commands that the REPL understands. Any other line is part of a block.
+const REPL_COMMANDS: [&str; 5] = ["step", "run", "regs", "heap", "reset"];
+
+// Lets the REPL and the program it runs take turns writing to one output.
+struct SharedOut<'a, 'b>(&'a RefCell<&'b mut dyn Write>);
+
+impl<'a, 'b> Write for SharedOut<'a, 'b> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.borrow_mut().flush()
+    }
+}
+
+// Parses and checks the blocks that the REPL has been given so far.
+fn repl_program(
+    source: &str,
+) -> Result<(HashMap<i32, syntax::Instr>, syntax::SourceMap), Error> {
+    if source.trim().is_empty() {
+        return Ok((HashMap::new(), syntax::SourceMap::new()));
+    }
+    let (blocks, positions) = parser::parse_with_positions(source)?;
+    Ok((tc::tc(blocks)?, positions))
+}
+
+// Runs one REPL command against `machine`, returning the response.
+fn repl_command(machine: &mut eval::Machine, command: &str) -> String {
+    match command {
+        "step" => match machine.step() {
+            Ok(eval::StepResult::Running) => {
+                format!("in block {}", machine.block())
+            }
+            Ok(eval::StepResult::Paused(id)) => {
+                format!("breakpoint: block {}", id)
+            }
+            Ok(eval::StepResult::Exited(n)) => format!("exited with {}", n),
+            Err(err) => err.to_string(),
+        },
+        "run" => loop {
+            match machine.step() {
+                Ok(eval::StepResult::Running) => (),
+                Ok(eval::StepResult::Paused(id)) => {
+                    break format!("breakpoint: block {}", id)
+                }
+                Ok(eval::StepResult::Exited(n)) => {
+                    break format!("exited with {}", n)
+                }
+                Err(err) => break err.to_string(),
+            }
+        },
+        "regs" => {
+            let regs: Vec<String> = machine
+                .registers()
+                .iter()
+                .enumerate()
+                .map(|(r, val)| format!("r{} = {}", r, val))
+                .collect();
+            regs.join("\n")
+        }
+        "heap" => {
+            let blocks: Vec<String> = machine
+                .allocated_blocks()
+                .into_iter()
+                .map(|(base, vals)| {
+                    let vals: Vec<String> = vals
+                        .into_iter()
+                        .map(|val| match val {
+                            Some(val) => val.to_string(),
+                            None => "?".to_string(),
+                        }).collect();
+                    format!("{}: [{}]", base, vals.join(", "))
+                }).collect();
+            if blocks.is_empty() {
+                "no allocated blocks".to_string()
+            } else {
+                blocks.join("\n")
+            }
+        }
+        _ => "restarted".to_string(),
+    }
+}
+
+// Reads blocks and commands from `input` a line at a time, starting with the
+// blocks in `source`, and writes responses to `out`. A block may span several
+// lines. Entering a block adds it to the program and restarts the program
+// from block 0. The commands are `step`, which runs one instruction, `run`,
+// which runs until the program exits or reaches a breakpoint, `regs` and
+// `heap`, which show the registers and allocated blocks, and `reset`, which
+// restarts the program.
+fn repl(
+    source: &str,
+    input: &mut dyn BufRead,
+    out: &mut dyn Write,
+    config: &eval::Config,
+) -> Result<(), Error> {
+    let out = RefCell::new(out);
+    let mut source = source.to_string();
+    let mut pending = String::new();
+    loop {
+        let program = repl_program(&source);
+        let mut empty = io::empty();
+        let mut prog_out = SharedOut(&out);
+        let mut stderr = io::stderr();
+        let mut machine = match &program {
+            Ok((blocks, positions)) => {
+                let io = eval::Io {
+                    input: &mut empty,
+                    out: &mut prog_out,
+                    trace: if config.trace { Some(&mut stderr) } else { None },
+                    dump: None,
+                };
+                let heap = Box::new(eval::LazyHeap::new(config.heap_size));
+                eval::Machine::new(config, blocks, positions, heap, io)
+                    .map_err(|err| err.to_string())
+            }
+            Err(err) => Err(err.to_string()),
+        };
+        loop {
+            let prompt = if pending.is_empty() { "> " } else { ". " };
+            write!(out.borrow_mut(), "{}", prompt)?;
+            out.borrow_mut().flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            let command = line.trim();
+            if pending.is_empty() && REPL_COMMANDS.contains(&command) {
+                let response = match &mut machine {
+                    Ok(machine) => repl_command(machine, command),
+                    Err(msg) => msg.clone(),
+                };
+                writeln!(out.borrow_mut(), "{}", response)?;
+                if command == "reset" {
+                    break;
+                }
+                continue;
+            }
+            if pending.is_empty() && command.is_empty() {
+                continue;
+            }
+            pending.push_str(&line);
+            if pending.matches('{').count() > pending.matches('}').count() {
+                continue;
+            }
+            let extended = format!("{}\n{}", source, pending);
+            pending.clear();
+            match repl_program(&extended) {
+                Ok(_) => {
+                    source = extended;
+                    writeln!(out.borrow_mut(), "ok")?;
+                    break;
+                }
+                Err(err) => writeln!(out.borrow_mut(), "{}", err)?,
+            }
+        }
+    }
+}
+
+// What a program read, printed, and exited with.
+struct Run<'a> {
+    input: &'a str,
+    output: &'a str,
+    result: i32,
+}
+
+// A test for the `tests` module below that runs `code` with `config` and the
+// input from `run`, and checks that it prints and exits with what `run` did.
+fn gen_test(
+    name: &str,
+    code: &str,
+    config: &eval::Config,
+    run: &Run,
+) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    // Use enough #s that the raw string can't end inside the program.
+    let mut hashes = "#".to_string();
+    while code.contains(&format!("\"{}", hashes)) {
+        hashes.push('#');
+    }
+    let timeout = match config.timeout {
+        Some(d) => format!(
+            "Some(std::time::Duration::new({}, {}))",
+            d.as_secs(),
+            d.subsec_nanos()
+        ),
+        None => "None".to_string(),
+    };
+    format!(
+        r#"    #[test]
+    fn test_{}() {{
+        let (r, out) = parse_and_eval_io(
+            r{}"{}"{},
+            {:?},
+            super::eval::Config {{
+                start: {},
+                heap_size: {},
+                num_registers: {},
+                coroutines: {},
+                strict_bounds: {},
+                args: vec!{:?},
+                step_limit: {:?},
+                timeout: {},
+                deterministic: {},
+                overflow: super::eval::Overflow::{:?},
+                alloc: super::eval::Alloc::{:?},
+                poison: {},
+                ..Default::default()
+            }},
+        ).unwrap();
+        assert_eq!(r, {});
+        assert_eq!(out, {:?});
+    }}
+"#,
+        name.to_lowercase(),
+        hashes,
+        code,
+        hashes,
+        run.input,
+        config.start,
+        config.heap_size,
+        config.num_registers,
+        config.coroutines,
+        config.strict_bounds,
+        config.args,
+        config.step_limit,
+        timeout,
+        config.deterministic,
+        config.overflow,
+        config.alloc,
+        config.poison,
+        run.result,
+        run.output
+    )
+}
+
+// Quotes `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut out = "\"".to_string();
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => {
+                out.push_str(&format!("\\u{:04x}", c as u32))
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// The outcome of a run and what the program printed as a JSON object, for
+// --json-result.
+fn json_result(result: &Result<i32, Error>, output: &str) -> String {
+    let (status, code, message) = match result {
+        Ok(r) => ("exit", *r, "null".to_string()),
+        Err(err @ Error::Abort { .. }) => {
+            ("abort", 1, json_string(&err.to_string()))
+        }
+        Err(err) => ("error", 1, json_string(&err.to_string())),
+    };
+    format!(
+        "{{\"status\": \"{}\", \"code\": {}, \"message\": {}, \
+         \"output\": {}}}",
+        status,
+        code,
+        message,
+        json_string(output)
+    )
 }
 
-fn main_result() -> Result<i32, Error> {
-    let args = App::new("ILVM")
+fn app() -> App<'static, 'static> {
+    App::new("ILVM")
         .version(env!("CARGO_PKG_VERSION"))
         .arg(
             Arg::with_name("memlimit")
@@ -40,8 +523,8 @@ fn main_result() -> Result<i32, Error> {
         ).arg(
             Arg::with_name("INPUT")
                 .value_name("FILENAME")
-                .help("Sets the input file to use")
-                .required(true)
+                .help("Sets the input files, or - for stdin (the default)")
+                .multiple(true)
                 .index(1),
         ).arg(
             Arg::with_name("reglimit")
@@ -50,35 +533,395 @@ fn main_result() -> Result<i32, Error> {
                 .default_value("32")
                 .long("num-registers")
                 .help("Set the number of registers"),
-        ).get_matches();
-    let path = args.value_of("INPUT").unwrap();
-    let mut file = try!(File::open(&path));
-    let mut buf = String::new();
-    try!(file.read_to_string(&mut buf));
-    parse_and_eval(
-        &buf[..],
-        args.value_of("memlimit").unwrap().parse::<usize>().unwrap(),
-        args.value_of("reglimit").unwrap().parse::<usize>().unwrap(),
-    )
+        ).arg(
+            Arg::with_name("warninfinite")
+                .long("warn-infinite")
+                .help("Warn about cycles of blocks that can never exit"),
+        ).arg(
+            Arg::with_name("warnbadstore")
+                .long("warn-bad-store")
+                .help("Warn about stores through non-pointer registers"),
+        ).arg(
+            Arg::with_name("warnunreachable")
+                .long("warn-unreachable")
+                .help("Warn about blocks that no path from block 0 reaches"),
+        ).arg(
+            Arg::with_name("checkinit")
+                .long("check-init")
+                .help("Reject reads of registers that may not be written yet"),
+        ).arg(
+            Arg::with_name("optimize")
+                .long("optimize")
+                .help("Fold constant arithmetic and branches before running"),
+        ).arg(
+            Arg::with_name("dumpast")
+                .long("dump-ast")
+                .help("Print the parsed program instead of running it"),
+        ).arg(
+            Arg::with_name("emitjson")
+                .long("emit-json")
+                .help("Print the parsed program as JSON instead of running it"),
+        ).arg(
+            Arg::with_name("check")
+                .long("check")
+                .help("Check the program and report its registers only"),
+        ).arg(
+            Arg::with_name("dumptags")
+                .long("dump-tags")
+                .help("Print the values recorded by tag instructions"),
+        ).arg(
+            Arg::with_name("dumpcaptures")
+                .long("dump-captures")
+                .help("Print the heap contents copied by capture instructions"),
+        ).arg(
+            Arg::with_name("deny")
+                .long("deny")
+                .value_name("KINDS")
+                .use_delimiter(true)
+                .help("Reject programs that use these instruction kinds"),
+        ).arg(
+            Arg::with_name("coroutines")
+                .long("coroutines")
+                .help("Run spawned blocks as coroutines that take turns"),
+        ).arg(
+            Arg::with_name("strictbounds")
+                .long("strict-bounds")
+                .help("Check that loads and stores stay inside live blocks"),
+        ).arg(
+            Arg::with_name("poison")
+                .long("poison")
+                .help("Fill malloc'd blocks with 0xdeadbeef instead of zeros"),
+        ).arg(
+            Arg::with_name("gentest")
+                .long("gen-test")
+                .help("Run the program and print a Rust test of what it does"),
+        ).arg(
+            Arg::with_name("steplimit")
+                .long("step-limit")
+                .value_name("STEPS")
+                .help("Stop with an error after this many instructions"),
+        ).arg(
+            Arg::with_name("timeout")
+                .long("timeout")
+                .value_name("SECONDS")
+                .help("Stop with an error after running this long"),
+        ).arg(
+            Arg::with_name("deterministic")
+                .long("deterministic")
+                .help("Ignore --timeout, so that every run behaves the same"),
+        ).arg(
+            Arg::with_name("trace")
+                .long("trace")
+                .help("Print each instruction to stderr before running it"),
+        ).arg(
+            Arg::with_name("break")
+                .long("break")
+                .value_name("BLOCK")
+                .multiple(true)
+                .number_of_values(1)
+                .allow_hyphen_values(true)
+                .help("Pause at the start of this block"),
+        ).arg(
+            Arg::with_name("dumponerror")
+                .long("dump-on-error")
+                .help("Print the registers and heap to stderr on an error"),
+        ).arg(
+            Arg::with_name("repl")
+                .long("repl")
+                .help("Enter blocks and step through them interactively"),
+        ).arg(
+            Arg::with_name("verbose")
+                .long("verbose")
+                .help("Print counts of what the program did to stderr"),
+        ).arg(
+            Arg::with_name("arg")
+                .long("arg")
+                .value_name("INT")
+                .multiple(true)
+                .number_of_values(1)
+                .allow_hyphen_values(true)
+                .help("Pass an argument to the program (in r0 and r1)"),
+        ).arg(
+            Arg::with_name("overflow")
+                .long("overflow")
+                .value_name("MODE")
+                .possible_values(&["checked", "wrapping"])
+                .default_value("checked")
+                .help("Stop at or wrap around on arithmetic overflow"),
+        ).arg(
+            Arg::with_name("alloc")
+                .long("alloc")
+                .value_name("STRATEGY")
+                .possible_values(&["first-fit", "best-fit"])
+                .default_value("first-fit")
+                .help("Choose which free block malloc takes memory from"),
+        ).arg(
+            Arg::with_name("exitcode")
+                .long("exit-code")
+                .help("Exit with the program's result, clamped to 0-255"),
+        ).arg(
+            Arg::with_name("jsonresult")
+                .long("json-result")
+                .help("Report how the program ended as a JSON object"),
+        )
+}
+
+fn parse_arg(s: &str) -> Result<i32, Error> {
+    s.parse::<i32>()
+        .map_err(|_| Error::Usage(format!("--arg {} is not an integer", s)))
+}
+
+fn parse_breakpoint(s: &str) -> Result<i32, Error> {
+    s.parse::<i32>().map_err(|_| {
+        Error::Usage(format!("--break {} is not a block number", s))
+    })
+}
+
+fn parse_heap_size(s: &str) -> Result<usize, Error> {
+    let msg = match s.parse::<usize>() {
+        Ok(words) if words >= eval::MIN_HEAP_SIZE => return Ok(words),
+        Ok(_) => "is too small",
+        Err(_) => "is not a number",
+    };
+    Err(Error::Usage(format!("--memory-limit {} {}", s, msg)))
+}
+
+fn parse_step_limit(s: &str) -> Result<u64, Error> {
+    s.parse::<u64>().map_err(|_| {
+        Error::Usage(format!("--step-limit {} is not a number", s))
+    })
+}
+
+fn parse_timeout(s: &str) -> Result<Duration, Error> {
+    s.parse::<f64>()
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .ok_or_else(|| {
+            Error::Usage(format!("--timeout {} is not a number of seconds", s))
+        })
+}
+
+// Returns the program's result, or `None` if we printed something else
+// instead.
+// Runs the program as `args` say, sending what it prints to `out`.
+fn main_result(
+    args: &ArgMatches,
+    out: &mut dyn Write,
+) -> Result<Option<i32>, Error> {
+    // The REPL reads its commands from stdin, so it only reads a program from
+    // files that are named.
+    let paths: Vec<&str> = match args.values_of("INPUT") {
+        Some(paths) => paths.collect(),
+        None if args.is_present("repl") => Vec::new(),
+        None => vec!["-"],
+    };
+    let mut bufs = Vec::new();
+    for path in &paths {
+        let mut buf = String::new();
+        if *path == "-" {
+            io::stdin().read_to_string(&mut buf)?;
+        } else {
+            File::open(path)
+                .and_then(|mut file| file.read_to_string(&mut buf))
+                .map_err(|err| {
+                    io::Error::new(err.kind(), format!("{}: {}", path, err))
+                })?;
+        }
+        bufs.push(buf);
+    }
+    let files: Vec<(&str, &str)> =
+        paths.iter().cloned().zip(bufs.iter().map(|b| &b[..])).collect();
+    if args.is_present("dumpast") {
+        let (blocks, _, _) = parse_files(&files)?;
+        print!("{}", syntax::format_blocks(&blocks));
+        return Ok(None);
+    }
+    if args.is_present("emitjson") {
+        let (blocks, _, _) = parse_files(&files)?;
+        println!("{}", blocks_json(&blocks)?);
+        return Ok(None);
+    }
+    let prog_args = args
+        .values_of("arg")
+        .map(|vals| vals.map(parse_arg).collect())
+        .unwrap_or_else(|| Ok(Vec::new()))?;
+    let mut opts = Options {
+        config: eval::Config {
+            start: 0,
+            heap_size: parse_heap_size(args.value_of("memlimit").unwrap())?,
+            num_registers: args
+                .value_of("reglimit")
+                .unwrap()
+                .parse::<usize>()
+                .unwrap(),
+            coroutines: args.is_present("coroutines"),
+            strict_bounds: args.is_present("strictbounds"),
+            poison: args.is_present("poison"),
+            args: prog_args,
+            trace: args.is_present("trace"),
+            dump_on_error: args.is_present("dumponerror"),
+            step_limit: args
+                .value_of("steplimit")
+                .map(parse_step_limit)
+                .transpose()?,
+            timeout: args
+                .value_of("timeout")
+                .map(parse_timeout)
+                .transpose()?,
+            deterministic: args.is_present("deterministic"),
+            overflow: match args.value_of("overflow") {
+                Some("wrapping") => eval::Overflow::Wrapping,
+                _ => eval::Overflow::Checked,
+            },
+            alloc: match args.value_of("alloc") {
+                Some("best-fit") => eval::Alloc::BestFit,
+                _ => eval::Alloc::FirstFit,
+            },
+            breakpoints: args
+                .values_of("break")
+                .map(|ids| ids.map(parse_breakpoint).collect())
+                .unwrap_or_else(|| Ok(Vec::new()))?,
+        },
+        warn_infinite: args.is_present("warninfinite"),
+        warn_bad_store: args.is_present("warnbadstore"),
+        warn_unreachable: args.is_present("warnunreachable"),
+        check_init: args.is_present("checkinit"),
+        optimize: args.is_present("optimize"),
+        dump_tags: args.is_present("dumptags"),
+        dump_captures: args.is_present("dumpcaptures"),
+        verbose: args.is_present("verbose"),
+        deny: args
+            .values_of("deny")
+            .map(|kinds| kinds.map(String::from).collect())
+            .unwrap_or_default(),
+    };
+    if args.is_present("repl") {
+        // The REPL reports errors in the program itself, and carries on.
+        if let Ok((_, _, settings)) = parse_files(&files) {
+            apply_settings(&mut opts.config, &settings, args);
+        }
+        let stdin = io::stdin();
+        let source = bufs.join("\n");
+        repl(&source, &mut stdin.lock(), &mut io::stdout(), &opts.config)?;
+        return Ok(None);
+    }
+    let (blocks, positions, settings) = parse_files(&files)?;
+    apply_settings(&mut opts.config, &settings, args);
+    if args.is_present("check") {
+        check_blocks(blocks, &opts)?;
+        return Ok(None);
+    }
+    if args.is_present("gentest") {
+        // Record the input and output, so that the test can check them.
+        let mut input = String::new();
+        if !paths.contains(&"-") {
+            io::stdin().read_to_string(&mut input)?;
+        }
+        let mut out = Vec::new();
+        let r = check_and_eval(
+            blocks,
+            positions,
+            &opts,
+            &mut input.as_bytes(),
+            &mut out,
+        )?;
+        let name = match paths[0] {
+            "-" => "stdin".into(),
+            path => Path::new(path).file_stem().unwrap().to_string_lossy(),
+        };
+        let run = Run {
+            input: &input,
+            output: &String::from_utf8_lossy(&out),
+            result: r,
+        };
+        // The files together, since block labels may cross between them.
+        print!("{}", gen_test(&name, &bufs.join("\n"), &opts.config, &run));
+        return Ok(None);
+    }
+    let stdin = io::stdin();
+    let r = check_and_eval(blocks, positions, &opts, &mut stdin.lock(), out)?;
+    Ok(Some(r))
 }
 
 fn main() {
-    match main_result() {
-        Ok(r) => println!("Normal termination. Result = {}", r),
-        Err(err) => {
-            println!("An error occurred.\n{}", err);
-            process::exit(1)
+    let args = app().get_matches();
+    let r = if args.is_present("jsonresult") {
+        // What the program prints goes in the JSON object, so that the object
+        // is all that reaches stdout.
+        let mut out = Vec::new();
+        let result = main_result(&args, &mut out);
+        let output = String::from_utf8_lossy(&out);
+        match result {
+            Ok(None) => return,
+            Ok(Some(r)) => {
+                println!("{}", json_result(&Ok(r), &output));
+                r
+            }
+            Err(err) => {
+                println!("{}", json_result(&Err(err), &output));
+                process::exit(1)
+            }
+        }
+    } else {
+        match main_result(&args, &mut io::stdout()) {
+            Ok(Some(r)) => {
+                println!("Normal termination. Result = {}", r);
+                r
+            }
+            Ok(None) => return,
+            Err(err) => {
+                println!("An error occurred.\n{}", err);
+                process::exit(1)
+            }
         }
+    };
+    if args.is_present("exitcode") {
+        process::exit(r.clamp(0, 255))
     }
 }
 
 #[cfg(test)]
 mod tests {
 
-    use super::syntax::{Val, Printable, Instr};
+    use ilvm::syntax::{Val, Printable, Instr, Op1, Op2, Pos};
+    use std::collections::{HashMap, HashSet};
+
+    fn parse_and_eval(code: &str) -> Result<i32, super::Error> {
+        parse_and_eval_with(code, super::eval::Config {
+            heap_size: 500,
+            num_registers: 10,
+            ..Default::default()
+        })
+    }
+
+    fn parse_and_eval_with(
+        code: &str,
+        config: super::eval::Config,
+    ) -> Result<i32, super::Error> {
+        parse_and_eval_io(code, "", config).map(|(r, _)| r)
+    }
 
-    fn parse_and_eval(code: &str) -> Result<i32, super::error::Error> {
-        super::parse_and_eval(code, 500, 10)
+    // Runs `code` with `input` on stdin, returning its result and what it
+    // printed.
+    fn parse_and_eval_io(
+        code: &str,
+        input: &str,
+        config: super::eval::Config,
+    ) -> Result<(i32, String), super::Error> {
+        let opts = super::Options {
+            config,
+            ..Default::default()
+        };
+        let (blocks, positions, _) = super::parse_files(&[("test", code)])?;
+        let mut out = Vec::new();
+        let r = super::check_and_eval(
+            blocks,
+            positions,
+            &opts,
+            &mut input.as_bytes(),
+            &mut out,
+        )?;
+        Ok((r, String::from_utf8(out).unwrap()))
     }
 
     fn assert_code_eq_block(code : &str, expected_block : Instr) {
@@ -88,13 +931,13 @@ mod tests {
                     Result::Ok(blocks) =>
                     match blocks.get(&0) {
                         Option::Some(block) => assert_eq!(*block, expected_block),
-                        _ => assert!(false, "no zero block found in")
+                        _ => panic!("no zero block found in")
                     }
-                    _ => assert!(false, "tc returned Error")
+                    _ => panic!("tc returned Error")
                 }
             }
-            Result::Err(super::Error::Parse(s)) => assert!(false, format!("parse error, {}", s)),
-            _ => assert!(false, format!("parse returned Error on input, {}", code))
+            Result::Err(super::Error::Parse(s)) => panic!("parse error, {}", s),
+            _ => panic!("parse returned Error on input, {}", code)
         };
     }
 
@@ -136,156 +979,3079 @@ mod tests {
     }
 
     #[test]
-    fn test_print_array_parsing() {
-        let code =
-            r#"
+    fn test_line_comments() {
+        let commented = r#"
+            // Start here.
+            block 0 { // The only block.
+                r0 = 10 / 2; // Halve it.
+                // exit(1);
+                exit(r0);
+            }
+            // Done."#;
+        let plain = r#"
             block 0 {
-                print(array(r0, 6));
-                exit(200);
+                r0 = 10 / 2;
+                exit(r0);
             }"#;
-        let expected_block =
-            Instr::Print(Printable::Array(Val::Reg(0), Val::Imm(6)),
-            Box::new(Instr::Exit(Val::Imm(200))
-        ));
-        assert_code_eq_block(code, expected_block);
+        assert_eq!(
+            super::parser::parse(commented).unwrap(),
+            super::parser::parse(plain).unwrap()
+        );
     }
 
     #[test]
-    fn test_exit() {
-        let r = parse_and_eval(
-            r#"
-            block 0 {
-                exit(200);
-            }"#,
-        ).unwrap();
-        assert!(r == 200);
+    fn test_block_comments() {
+        let parse = |code| super::parser::parse(code).unwrap();
+        let plain = parse("block 0 { r0 = 2 * 3; exit(r0); }");
+        let single = "block 0 { /* six */ r0 = 2 * 3; exit(r0); }";
+        assert_eq!(parse(single), plain);
+        let multi = r#"
+            /*
+            block 1 {
+                exit(1);
+            }
+            */
+            block 0 { r0 = 2 * 3; exit(r0); }"#;
+        assert_eq!(parse(multi), plain);
+        let nested = "/* outer /* inner */ still commented */ block 0 {
+            r0 = 2 * 3; exit(r0); }";
+        assert_eq!(parse(nested), plain);
     }
 
     #[test]
-    fn test_reg_copy() {
-        let r = parse_and_eval(
-            r#"
-            block 0 {
-                r0 = 200;
-                r2 = r0;
-                exit(r2);
-            }"#,
-        ).unwrap();
-        assert!(r == 200);
+    fn test_unterminated_block_comment() {
+        match super::parser::parse("block 0 { exit(0); } /* /* */") {
+            Err(super::Error::Parse(msg)) => {
+                assert!(msg.contains("unterminated block comment"), "{}", msg)
+            }
+            _ => panic!("expected a parse error"),
+        }
     }
 
     #[test]
-    fn test_reg_add() {
-        let r = parse_and_eval(
-            r#"
-            block 0 {
-                r0 = 200;
-                r1 = 11;
-                r3 = r0 + r1;
-                exit(r3);
-            }"#,
-        ).unwrap();
-        assert!(r == 211);
+    fn test_decimal_overflow() {
+        match parse_and_eval("block 0 { r0 = 9999999999; exit(r0); }") {
+            Err(super::Error::Parse(_)) => (),
+            _ => panic!("expected a parse error"),
+        }
+        match parse_and_eval("block 0 { r0 = 2147483648; exit(r0); }") {
+            Err(super::Error::Parse(_)) => (),
+            _ => panic!("expected a parse error"),
+        }
+        let r = parse_and_eval("block 0 { r0 = -2147483648; exit(r0); }");
+        assert_eq!(r.unwrap(), i32::MIN);
     }
 
     #[test]
-    fn test_load_store() {
-        let r = parse_and_eval(
-            r#"
-            block 0 {
-                r0 = 200;
-                *r0 = 42;
-                r1 = *r0;
-                exit(r1);
-            }"#,
-        ).unwrap();
-        assert!(r == 42);
+    fn test_hex_literals() {
+        let code = "block 0 { r0 = 0xff; r1 = -0x10; exit(r0); }";
+        let expected_block = Instr::Copy(
+            0,
+            Val::Imm(255),
+            Box::new(Instr::Copy(
+                1,
+                Val::Imm(-16),
+                Box::new(Instr::Exit(Val::Reg(0))),
+            )),
+        );
+        assert_code_eq_block(code, expected_block);
+        match super::parser::parse("block 0 { exit(0xffffffff); }") {
+            Err(super::Error::Parse(_)) => (),
+            _ => panic!("expected a parse error"),
+        }
     }
 
     #[test]
-    fn test_goto() {
-        let r = parse_and_eval(
-            r#"
-            block 0 {
-                r2 = 200;
-                goto(10);
-            }
-            block 10 {
-                r2 = r2 + 1;
-                exit(r2);
-            }"#,
-        ).unwrap();
-        assert!(r == 201);
+    fn test_negative_block_id() {
+        let code = "block 0 { goto(-1); } block -1 { exit(7); }";
+        assert_eq!(parse_and_eval(code).unwrap(), 7);
+        let code = "block 0 { goto(-16); } block -0x10 { exit(7); }";
+        assert_eq!(parse_and_eval(code).unwrap(), 7);
+        let code = "block 0 { goto(1); } block +1 { exit(7); }";
+        assert_eq!(parse_and_eval(code).unwrap(), 7);
     }
 
     #[test]
-    fn test_indirect_goto() {
-        let r = parse_and_eval(
-            r#"
-            block 0 {
-                r2 = 200;
-                r3 = 10;
-                goto(r3);
-            }
-            block 10 {
-                r2 = r2 + r3;
-                exit(r2);
-            }"#,
-        ).unwrap();
-        assert!(r == 210);
+    fn test_binary_literals() {
+        let code = "block 0 { r0 = 0b1010; r1 = -0b1; exit(r0); }";
+        let expected_block = Instr::Copy(
+            0,
+            Val::Imm(10),
+            Box::new(Instr::Copy(
+                1,
+                Val::Imm(-1),
+                Box::new(Instr::Exit(Val::Reg(0))),
+            )),
+        );
+        assert_code_eq_block(code, expected_block);
+        let code = format!("block 0 {{ exit(0b1{}); }}", "0".repeat(32));
+        match super::parser::parse(&code) {
+            Err(super::Error::Parse(_)) => (),
+            _ => panic!("expected a parse error"),
+        }
     }
 
     #[test]
-    fn test_ifz() {
-        let r = parse_and_eval(
-            r#"
-            block 0 {
-                r2 = 1;
-                ifz r2 {
-                    exit(20);
-                }
-                else {
-                    exit(30);
-                }
+    fn test_digit_separators() {
+        let code = "block 0 { r0 = 1_000_000; r1 = 0xff_ff; exit(r0); }";
+        let expected_block = Instr::Copy(
+            0,
+            Val::Imm(1000000),
+            Box::new(Instr::Copy(
+                1,
+                Val::Imm(0xffff),
+                Box::new(Instr::Exit(Val::Reg(0))),
+            )),
+        );
+        assert_code_eq_block(code, expected_block);
+        for n in &["_5", "5_", "5__0", "0x_ff"] {
+            let code = format!("block 0 {{ exit({}); }}", n);
+            match super::parser::parse(&code) {
+                Err(super::Error::Parse(_)) => (),
+                _ => panic!("expected a parse error for {}", n),
+            }
+        }
+    }
+
+    #[test]
+    fn test_code_after_terminator_rejected() {
+        // Terminators have no rest, so the parser rejects whatever follows.
+        for end in &["exit(0);", "goto(0);", "abort;", "ret(0);"] {
+            let code = format!("block 0 {{\n  {}\n  r0 = 1;\n}}", end);
+            match super::parser::parse(&code) {
+                Err(super::Error::Parse(msg)) => assert!(
+                    msg.starts_with("Parse error at line 3, column 3"),
+                    "{}",
+                    msg
+                ),
+                _ => panic!("expected a parse error after {}", end),
+            }
+        }
+        let branch =
+            "block 0 { ifz r0 { exit(0); r0 = 1; } else { exit(1); } }";
+        assert!(super::parser::parse(branch).is_err());
+        // Neither branch can fall out of an ifz, so an ifz ends its block too.
+        let after_ifz = concat!(
+            "block 0 {\n",
+            "  ifz r0 { goto(1); } else { goto(2); }\n",
+            "  r0 = 1;\n",
+            "}\n",
+            "block 1 { exit(1); }\nblock 2 { exit(2); }",
+        );
+        match super::parser::parse(after_ifz) {
+            Err(super::Error::Parse(msg)) => assert!(
+                msg.starts_with("Parse error at line 3, column 3"),
+                "{}",
+                msg
+            ),
+            _ => panic!("expected a parse error after ifz"),
+        }
+        let joined = "block 0 { ifz r0 { goto(1); } else { goto(2); } }\n\
+                      block 1 { r1 = 1; goto(3); }\n\
+                      block 2 { r1 = 2; goto(3); }\n\
+                      block 3 { r0 = r1 * 10; exit(r0); }";
+        assert_eq!(parse_and_eval(joined).unwrap(), 10);
+        let normal =
+            "block 0 { r0 = 1; ifz r0 { exit(0); } else { exit(r0); } }";
+        assert_eq!(parse_and_eval(normal).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_parse_error_position() {
+        let code = "block 0 {\n  r0 = 1;\n  r1 = ;\n  exit(r0);\n}";
+        match super::parser::parse(code) {
+            Err(super::Error::Parse(msg)) => {
+                assert!(msg.contains("line 3, column 8"), "{}", msg)
+            }
+            _ => panic!("expected a parse error"),
+        }
+        match super::parser::parse("block 0 {\n  exit(0);\n} @") {
+            Err(super::Error::Parse(msg)) => {
+                assert!(msg.contains("line 3, column 3"), "{}", msg)
+            }
+            _ => panic!("expected a parse error"),
+        }
+    }
+
+    #[test]
+    fn test_bitwise() {
+        let code = "block 0 { r0 = 6 & 3; exit(r0); }";
+        let expected_block = Instr::Op2(
+            0,
+            Op2::And,
+            Val::Imm(6),
+            Val::Imm(3),
+            Box::new(Instr::Exit(Val::Reg(0))),
+        );
+        assert_code_eq_block(code, expected_block);
+        assert_eq!(parse_and_eval(code).unwrap(), 2);
+        let r = parse_and_eval("block 0 { r0 = 6 | 3; exit(r0); }");
+        assert_eq!(r.unwrap(), 7);
+        let r = parse_and_eval("block 0 { r0 = 6 ^ 3; exit(r0); }");
+        assert_eq!(r.unwrap(), 5);
+    }
+
+    #[test]
+    fn test_shifts() {
+        let r = parse_and_eval("block 0 { r0 = 1 << 4; exit(r0); }");
+        assert_eq!(r.unwrap(), 16);
+        let r = parse_and_eval("block 0 { r0 = 256 >> 2; exit(r0); }");
+        assert_eq!(r.unwrap(), 64);
+        let r = parse_and_eval("block 0 { r0 = -256 >> 2; exit(r0); }");
+        assert_eq!(r.unwrap(), -64);
+        let r = parse_and_eval("block 0 { r0 = 3 < 4; exit(r0); }");
+        assert_eq!(r.unwrap(), 1);
+        match parse_and_eval("block 0 { r0 = 1 << 40; exit(r0); }") {
+            Err(super::Error::Runtime(msg)) => {
+                assert!(msg.ends_with("shift by 40 is out of range"), "{}", msg)
+            }
+            _ => panic!("expected a runtime error"),
+        }
+    }
+
+    #[test]
+    fn test_comparisons() {
+        let ops = [
+            (">", Op2::GT, [0, 0, 1]),
+            ("<=", Op2::LE, [1, 1, 0]),
+            (">=", Op2::GE, [0, 1, 1]),
+            ("!=", Op2::NE, [1, 0, 1]),
+        ];
+        for (text, op, results) in ops.iter() {
+            let code = format!("block 0 {{ r0 = r1 {} 4; exit(r0); }}", text);
+            let expected_block = Instr::Op2(
+                0,
+                op.clone(),
+                Val::Reg(1),
+                Val::Imm(4),
+                Box::new(Instr::Exit(Val::Reg(0))),
+            );
+            assert_code_eq_block(&code, expected_block);
+            for (m, expected) in [3, 4, 5].iter().zip(results.iter()) {
+                let code = format!(
+                    "block 0 {{ r0 = {} {} 4; exit(r0); }}",
+                    m, text
+                );
+                assert_eq!(parse_and_eval(&code).unwrap(), *expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_eq_parsing() {
+        let code = "block 0 { r0 = r1 == r2; exit(r0); }";
+        let expected_block = Instr::Op2(
+            0,
+            Op2::Eq,
+            Val::Reg(1),
+            Val::Reg(2),
+            Box::new(Instr::Exit(Val::Reg(0))),
+        );
+        assert_code_eq_block(code, expected_block);
+    }
+
+    #[test]
+    fn test_print_array_parsing() {
+        let code =
+            r#"
+            block 0 {
+                print(array(r0, 6));
+                exit(200);
+            }"#;
+        let expected_block =
+            Instr::Print(Printable::Array(Val::Reg(0), Val::Imm(6)),
+            Box::new(Instr::Exit(Val::Imm(200))
+        ));
+        assert_code_eq_block(code, expected_block);
+    }
+
+    #[test]
+    fn test_print_output() {
+        let blocks = super::parser::parse(
+            r#"
+            block 0 {
+                r0 = 7;
+                print("hello");
+                print(r0);
+                print(-3);
+                exit(0);
             }"#,
         ).unwrap();
-        assert!(r == 30);
+        let blocks = super::tc::tc(blocks).unwrap();
+        let mut out = Vec::new();
+        let io = super::eval::Io {
+            input: &mut std::io::empty(),
+            out: &mut out,
+            trace: None,
+            dump: None,
+        };
+        super::eval::eval_with_memory(
+            &super::eval::Config::default(),
+            blocks,
+            HashMap::new(),
+            Box::new(vec![0; 100]),
+            io,
+        ).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "hello\n7\n-3\n");
+    }
+
+    // Runs `code` and returns what it printed.
+    fn eval_output(code: &str) -> Result<String, super::Error> {
+        let blocks = super::tc::tc(super::parser::parse(code)?)?;
+        let mut out = Vec::new();
+        let io = super::eval::Io {
+            input: &mut std::io::empty(),
+            out: &mut out,
+            trace: None,
+            dump: None,
+        };
+        super::eval::eval_with_memory(
+            &super::eval::Config::default(),
+            blocks,
+            HashMap::new(),
+            Box::new(vec![0; 100]),
+            io,
+        )?;
+        Ok(String::from_utf8(out).unwrap())
     }
 
     #[test]
-    fn test_fac() {
+    fn test_print_seq_parsing() {
+        let code = "block 0 { print(seq(r2, 3)); exit(0); }";
+        let expected_block =
+            Instr::Print(Printable::Seq(Val::Reg(2), Val::Imm(3)),
+            Box::new(Instr::Exit(Val::Imm(0))
+        ));
+        assert_code_eq_block(code, expected_block);
+    }
+
+    #[test]
+    fn test_print_hex_parsing() {
+        let code = "block 0 { print(hex(r2)); exit(0); }";
+        let expected_block = Instr::Print(
+            Printable::Hex(Val::Reg(2)),
+            Box::new(Instr::Exit(Val::Imm(0))),
+        );
+        assert_code_eq_block(code, expected_block);
+    }
+
+    #[test]
+    fn test_print_hex() {
+        let out = eval_output(
+            "block 0 { r0 = 255; print(r0); print(hex(r0)); r1 = -1; \
+             print(r1); print(hex(r1)); print(hex(0)); exit(0); }",
+        ).unwrap();
+        assert_eq!(out, "255\n0xff\n-1\n0xffffffff\n0x0\n");
+    }
+
+    #[test]
+    fn test_print_seq() {
+        let out = eval_output(
+            r#"
+            block 0 {
+                r1 = 10;
+                r2 = 20;
+                r3 = 30;
+                print(seq(1, 3));
+                exit(0);
+            }"#,
+        ).unwrap();
+        assert_eq!(out, "[10, 20, 30]\n");
+    }
+
+    #[test]
+    fn test_print_array() {
+        let out = eval_output(
+            r#"
+            block 0 {
+                r0 = malloc(3);
+                r0[0] = 4;
+                r0[1] = 5;
+                r0[2] = 6;
+                print(array(r0, 3));
+                exit(0);
+            }"#,
+        ).unwrap();
+        assert_eq!(out, "[4, 5, 6]\n");
+    }
+
+    #[test]
+    fn test_print_evaluates_every_form() {
+        let out = eval_output(
+            r#"
+            block 0 {
+                r0 = 7;
+                r1 = malloc(2);
+                r2 = r0 * 2;
+                r1[0] = r2;
+                r2 = r0 * 3;
+                r1[1] = r2;
+                print("foo");
+                print(r0);
+                print(seq(0, 1));
+                print(array(r1, 2));
+                exit(0);
+            }"#,
+        ).unwrap();
+        assert_eq!(out, "foo\n7\n[7]\n[14, 21]\n");
+    }
+
+    #[test]
+    fn test_print_out_of_bounds() {
+        let seq = "block 0 { print(seq(30, 3)); exit(0); }";
+        match eval_output(seq) {
+            Err(super::Error::Runtime(msg)) =>
+                assert!(msg.contains("seq(30, 3) invalid register range")),
+            other => panic!("unexpected result {:?}", other),
+        }
+        let array = "block 0 { print(array(98, 3)); exit(0); }";
+        match eval_output(array) {
+            Err(super::Error::Runtime(msg)) =>
+                assert!(msg.contains("array(98, 3) invalid address range")),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_read_parsing() {
+        let code = "block 0 { r3 = read(); exit(r3); }";
+        let expected_block =
+            Instr::Read(3, Box::new(Instr::Exit(Val::Reg(3))));
+        assert_code_eq_block(code, expected_block);
+    }
+
+    #[test]
+    fn test_read() {
+        let blocks =
+            super::parser::parse("block 0 { r0 = read(); exit(r0); }")
+                .unwrap();
+        let blocks = super::tc::tc(blocks).unwrap();
+        let io = super::eval::Io {
+            input: &mut "  42\n".as_bytes(),
+            out: &mut Vec::new(),
+            trace: None,
+            dump: None,
+        };
+        let st = super::eval::eval_with_memory(
+            &super::eval::Config::default(),
+            blocks,
+            HashMap::new(),
+            Box::new(vec![0; 100]),
+            io,
+        ).unwrap();
+        assert_eq!(st.registers[0], 42);
+    }
+
+    #[test]
+    fn test_exit() {
         let r = parse_and_eval(
             r#"
             block 0 {
-                r2 = 1;
-                r1 = 5;
+                exit(200);
+            }"#,
+        ).unwrap();
+        assert!(r == 200);
+    }
+
+    #[test]
+    fn test_reg_copy() {
+        let r = parse_and_eval(
+            r#"
+            block 0 {
+                r0 = 200;
+                r2 = r0;
+                exit(r2);
+            }"#,
+        ).unwrap();
+        assert!(r == 200);
+    }
+
+    #[test]
+    fn test_reg_add() {
+        let r = parse_and_eval(
+            r#"
+            block 0 {
+                r0 = 200;
+                r1 = 11;
+                r3 = r0 + r1;
+                exit(r3);
+            }"#,
+        ).unwrap();
+        assert!(r == 211);
+    }
+
+    #[test]
+    fn test_step_reg_add() {
+        use super::eval::{Io, Machine, StepResult};
+        let code = "block 0 { r0 = 200; r1 = 11; r3 = r0 + r1; exit(r3); }";
+        let blocks =
+            super::tc::tc(super::parser::parse(code).unwrap()).unwrap();
+        let positions = HashMap::new();
+        let mut out = Vec::new();
+        let io = Io {
+            input: &mut std::io::empty(),
+            out: &mut out,
+            trace: None,
+            dump: None,
+        };
+        let config = super::eval::Config {
+            num_registers: 4,
+            ..Default::default()
+        };
+        let heap = Box::new(vec![0; 100]);
+        let mut machine =
+            Machine::new(&config, &blocks, &positions, heap, io).unwrap();
+        assert_eq!(machine.registers(), &[0, 0, 0, 0]);
+        assert_eq!(machine.step().unwrap(), StepResult::Running);
+        assert_eq!(machine.registers(), &[200, 0, 0, 0]);
+        assert_eq!(machine.step().unwrap(), StepResult::Running);
+        assert_eq!(machine.registers(), &[200, 11, 0, 0]);
+        assert_eq!(machine.step().unwrap(), StepResult::Running);
+        assert_eq!(machine.registers(), &[200, 11, 0, 211]);
+        assert_eq!(machine.step().unwrap(), StepResult::Exited(211));
+        assert_eq!(machine.next_instr(), None);
+        assert_eq!(machine.step().unwrap(), StepResult::Exited(211));
+    }
+
+    #[test]
+    fn test_free_forgets_blocks() {
+        use super::eval::{Io, Machine, StepResult};
+        let code = r#"
+            block 0 {
+                r0 = 50;
                 goto(1);
             }
             block 1 {
-                ifz r1 {
-                   exit(r2);
+                ifz r0 {
+                    exit(0);
                 }
                 else {
-                    r2 = r2 * r1;
-                    r1 = r1 - 1;
+                    r1 = malloc(3);
+                    r2 = malloc(1);
+                    free(r1);
+                    free(r2);
+                    r0 = r0 - 1;
                     goto(1);
                 }
+            }"#;
+        let blocks =
+            super::tc::tc(super::parser::parse(code).unwrap()).unwrap();
+        let positions = HashMap::new();
+        let mut out = Vec::new();
+        let io = Io {
+            input: &mut std::io::empty(),
+            out: &mut out,
+            trace: None,
+            dump: None,
+        };
+        let config = super::eval::Config {
+            breakpoints: vec![1],
+            ..Default::default()
+        };
+        // Too small for 50 blocks of 4 words that are never reused.
+        let heap = Box::new(vec![0; 100]);
+        let mut machine =
+            Machine::new(&config, &blocks, &positions, heap, io).unwrap();
+        let mut pauses = 0;
+        loop {
+            match machine.step().unwrap() {
+                StepResult::Running => (),
+                StepResult::Paused(_) => {
+                    pauses += 1;
+                    assert_eq!(machine.allocated_blocks(), []);
+                }
+                StepResult::Exited(n) => {
+                    assert_eq!(n, 0);
+                    break;
+                }
+            }
+        }
+        assert_eq!(pauses, 51);
+        assert_eq!(machine.allocated_blocks(), []);
+    }
+
+    #[test]
+    fn test_load_store() {
+        let r = parse_and_eval(
+            r#"
+            block 0 {
+                r0 = 200;
+                *r0 = 42;
+                r1 = *r0;
+                exit(r1);
             }"#,
         ).unwrap();
-        assert!(r == 120);
+        assert!(r == 42);
     }
 
     #[test]
-    fn test_malloc() {
+    fn test_store_address_parsing() {
+        let code = r#"
+            block 0 {
+                *5 = 1;
+                *r0 = r1;
+                exit(0);
+            }"#;
+        let expected_block = Instr::Store(Val::Imm(5), Val::Imm(1),
+            Box::new(Instr::Store(Val::Reg(0), Val::Reg(1),
+            Box::new(Instr::Exit(Val::Imm(0))))));
+        assert_code_eq_block(code, expected_block);
+    }
+
+    #[test]
+    fn test_store_immediate_address() {
         let r = parse_and_eval(
             r#"
             block 0 {
-                r0 = malloc(10);
+                *300 = 40;
+                r0 = 301;
+                *r0 = 2;
+                r1 = *300;
+                r2 = *r0;
+                r0 = r1 + r2;
                 exit(r0);
             }"#,
         ).unwrap();
-        assert!(r == 1);
+        assert!(r == 42);
+    }
+
+    #[test]
+    fn test_final_state() {
+        let blocks = super::parser::parse(
+            r#"
+            block 0 {
+                r0 = 200;
+                *r0 = 42;
+                r1 = 7;
+                exit(0);
+            }"#,
+        ).unwrap();
+        let blocks = super::tc::tc(blocks).unwrap();
+        let config = super::eval::Config {
+            heap_size: 500,
+            num_registers: 10,
+            ..Default::default()
+        };
+        let st =
+            super::eval::eval_with_state(&config, blocks, HashMap::new())
+                .unwrap();
+        assert_eq!(st.exit_code, 0);
+        assert_eq!(st.heap[200], 42);
+        assert_eq!(st.registers[..2], [200, 7]);
+    }
+
+    #[test]
+    fn test_large_heap_limit() {
+        let blocks = super::parser::parse(
+            r#"
+            block 0 {
+                r0 = malloc(4);
+                r0[3] = 9;
+                r1 = 99999999;
+                r2 = *r1;
+                exit(r2);
+            }"#,
+        ).unwrap();
+        let blocks = super::tc::tc(blocks).unwrap();
+        let config = super::eval::Config {
+            heap_size: 100000000,
+            ..Default::default()
+        };
+        let st =
+            super::eval::eval_with_state(&config, blocks, HashMap::new())
+                .unwrap();
+        assert_eq!(st.exit_code, 0);
+        // Only the words up to the last one written exist.
+        assert_eq!(st.heap, [0, 0, 0, 0, 9]);
+    }
+
+    #[test]
+    fn test_goto() {
+        let r = parse_and_eval(
+            r#"
+            block 0 {
+                r2 = 200;
+                goto(10);
+            }
+            block 10 {
+                r2 = r2 + 1;
+                exit(r2);
+            }"#,
+        ).unwrap();
+        assert!(r == 201);
+    }
+
+    #[test]
+    fn test_eval_from() {
+        let run = |code, start| {
+            let (blocks, positions) =
+                super::parser::parse_with_positions(code).unwrap();
+            let blocks = super::tc::tc(blocks).unwrap();
+            let config = super::eval::Config::default();
+            super::eval::eval_from(start, &config, blocks, positions)
+        };
+        let code = r#"
+            block 0 {
+                exit(1);
+            }
+            block 10 {
+                r0 = 5;
+                goto(11);
+            }
+            block 11 {
+                r0 = r0 * 2;
+                exit(r0);
+            }"#;
+        assert_eq!(run(code, 10).unwrap(), 10);
+        assert_eq!(run(code, 0).unwrap(), 1);
+        match run(code, 7) {
+            Err(super::Error::Usage(msg)) => {
+                assert_eq!(msg, "Expected block 7")
+            }
+            _ => panic!("expected a usage error"),
+        }
     }
 
+    #[test]
+    fn test_overflow() {
+        let code = "block 0 { r0 = 2147483647 + 1; exit(r0); }";
+        match parse_and_eval(code) {
+            Err(super::Error::Runtime(msg)) => {
+                assert!(msg.ends_with("arithmetic overflow"), "{}", msg)
+            }
+            _ => panic!("expected a runtime error"),
+        }
+        let r = parse_and_eval_with(code, super::eval::Config {
+            overflow: super::eval::Overflow::Wrapping,
+            ..Default::default()
+        });
+        assert_eq!(r.unwrap(), i32::MIN);
+    }
+
+    #[test]
+    fn test_min_max_parsing() {
+        let code = "block 0 { r0 = min(r1, 2); r3 = max(-4, r0); exit(r3); }";
+        let expected_block = Instr::Op2(0, Op2::Min, Val::Reg(1), Val::Imm(2),
+            Box::new(Instr::Op2(3, Op2::Max, Val::Imm(-4), Val::Reg(0),
+            Box::new(Instr::Exit(Val::Reg(3))))));
+        assert_code_eq_block(code, expected_block);
+    }
 
+    #[test]
+    fn test_min_max() {
+        let code = "block 0 { r0 = max(3, 7); r1 = min(r0, -2); \
+                    r2 = max(r1, r1); r0 = r0 * 100; r0 = r0 + r2; exit(r0); }";
+        assert_eq!(parse_and_eval(code).unwrap(), 698);
+    }
+
+    #[test]
+    fn test_pow() {
+        let code = "block 0 { r0 = 10; r1 = pow(2, r0); exit(r1); }";
+        assert_eq!(parse_and_eval(code).unwrap(), 1024);
+        assert_eq!(parse_and_eval("block 0 { r0 = pow(-3, 3); exit(r0); }")
+            .unwrap(), -27);
+        assert_eq!(parse_and_eval("block 0 { r0 = pow(0, 0); exit(r0); }")
+            .unwrap(), 1);
+        let errors = [
+            ("block 0 { r0 = pow(2, 40); exit(r0); }", "arithmetic overflow"),
+            ("block 0 { r0 = -1; r0 = pow(2, r0); exit(r0); }",
+             "negative exponent -1"),
+        ];
+        for (code, expected) in &errors {
+            match parse_and_eval(code) {
+                Err(super::Error::Runtime(msg)) => {
+                    assert!(msg.ends_with(expected), "{}", msg)
+                }
+                _ => panic!("expected a runtime error for {}", code),
+            }
+        }
+        let wrapping = super::eval::Config {
+            overflow: super::eval::Overflow::Wrapping,
+            ..Default::default()
+        };
+        let code = "block 0 { r0 = pow(3, 40); exit(r0); }";
+        assert_eq!(parse_and_eval_with(code, wrapping).unwrap(),
+                   3i32.wrapping_pow(40));
+    }
+
+    #[test]
+    fn test_abs_sign_parsing() {
+        let code = "block 0 { r0 = abs(r1); r2 = sign(-5); exit(r2); }";
+        let expected_block = Instr::Op1(0, Op1::Abs, Val::Reg(1),
+            Box::new(Instr::Op1(2, Op1::Sign, Val::Imm(-5),
+            Box::new(Instr::Exit(Val::Reg(2))))));
+        assert_code_eq_block(code, expected_block);
+    }
+
+    #[test]
+    fn test_abs_sign() {
+        let cases = [
+            ("block 0 { r0 = -7; r0 = abs(r0); exit(r0); }", 7),
+            ("block 0 { r0 = abs(7); exit(r0); }", 7),
+            ("block 0 { r0 = -7; r0 = sign(r0); exit(r0); }", -1),
+            ("block 0 { r0 = sign(0); exit(r0); }", 0),
+            ("block 0 { r0 = 42; r0 = sign(r0); exit(r0); }", 1),
+        ];
+        for (code, expected) in &cases {
+            assert_eq!(parse_and_eval(code).unwrap(), *expected, "{}", code);
+        }
+        let code = "block 0 { r0 = -2147483648; r1 = abs(r0); exit(r1); }";
+        match parse_and_eval(code) {
+            Err(super::Error::Runtime(msg)) => {
+                assert!(msg.ends_with("arithmetic overflow"), "{}", msg)
+            }
+            _ => panic!("expected a runtime error"),
+        }
+        let wrapping = super::eval::Config {
+            overflow: super::eval::Overflow::Wrapping,
+            ..Default::default()
+        };
+        assert_eq!(parse_and_eval_with(code, wrapping).unwrap(), i32::MIN);
+    }
+
+    #[test]
+    fn test_fast_op2_agrees() {
+        use super::eval::{eval_op2, fast_op2, Overflow};
+        let ops = [
+            Op2::Add, Op2::Sub, Op2::Mul, Op2::Div, Op2::Mod, Op2::LT,
+            Op2::Eq, Op2::GT, Op2::LE, Op2::GE, Op2::NE, Op2::And, Op2::Or,
+            Op2::Xor, Op2::Shl, Op2::Shr, Op2::Min, Op2::Max, Op2::Pow,
+        ];
+        let vals = [i32::MIN, -7, -1, 0, 1, 3, 31, 32, i32::MAX];
+        for overflow in &[Overflow::Checked, Overflow::Wrapping] {
+            for op in &ops {
+                let fast = fast_op2(op, *overflow);
+                for m in &vals {
+                    for n in &vals {
+                        // `None` must be exactly where `eval_op2` fails.
+                        let slow = eval_op2(op, *overflow, *m, *n).ok();
+                        assert_eq!(fast(*m, *n), slow, "{} {} {}", m, op, n);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_op2_run() {
+        // A run of `Op2`s goes in one step, but still counts every
+        // instruction and says which one failed.
+        let code = "block 0 {\n  r0 = 6;\n  r1 = r0 * 7;\n  r2 = r1 - 42;\n  \
+                    r3 = r1 / r2;\n  exit(r3);\n}";
+        let limited = |step_limit| super::eval::Config {
+            step_limit: Some(step_limit),
+            ..Default::default()
+        };
+        match parse_and_eval_with(code, limited(2)) {
+            Err(super::Error::Runtime(msg)) => assert_eq!(
+                msg,
+                "block 0, line 4, column 3: step limit exceeded"
+            ),
+            r => panic!("expected a runtime error, got {:?}", r),
+        }
+        match parse_and_eval_with(code, limited(5)) {
+            Err(err @ super::Error::DivByZero { .. }) => assert_eq!(
+                err.to_string(),
+                "block 0, line 5, column 3: division by zero"
+            ),
+            r => panic!("expected a division by zero, got {:?}", r),
+        }
+    }
+
+    // Times a million-iteration loop of mostly arithmetic, with checked and
+    // with wrapping arithmetic, reporting the fastest of several runs since
+    // the timings are noisy. Running each run of `Op2`s in one step took it
+    // from about 94ms to about 63ms in both modes. Run it with
+    // `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_op2_loop() {
+        let code = r#"
+            block 0 {
+                r0 = 1000000;
+                goto(1);
+            }
+            block 1 {
+                ifz r0 {
+                    exit(r3);
+                }
+                else {
+                    r1 = r1 ^ r0;
+                    r2 = r0 < 5;
+                    r3 = r3 + 1;
+                    r4 = r1 * 3;
+                    r4 = r4 - r2;
+                    r0 = r0 - 1;
+                    goto(1);
+                }
+            }"#;
+        for overflow in &[
+            super::eval::Overflow::Checked,
+            super::eval::Overflow::Wrapping,
+        ] {
+            let config = super::eval::Config {
+                overflow: *overflow,
+                ..Default::default()
+            };
+            let fastest = (0..10)
+                .map(|_| {
+                    let (blocks, positions) =
+                        super::parser::parse_with_positions(code).unwrap();
+                    let blocks = super::tc::tc(blocks).unwrap();
+                    let start = std::time::Instant::now();
+                    let r = super::eval::eval(&config, blocks, positions);
+                    assert_eq!(r.unwrap(), 1000000);
+                    start.elapsed()
+                }).min()
+                .unwrap();
+            eprintln!("{:?}: {:?}", overflow, fastest);
+        }
+    }
+
+    // Times a loop that mostly jumps, once with block IDs that are close
+    // together and once with IDs that are too far apart to index a vector.
+    // Run it with `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_jumps() {
+        for first in &[1, 100000000] {
+            let code = format!(
+                "block 0 {{ r0 = 1000000; goto({}); }} \
+                 block {} {{ goto(2); }} \
+                 block 2 {{ goto(3); }} \
+                 block 3 {{ r0 = r0 - 1; ifz r0 {{ exit(0); }} \
+                   else {{ goto({}); }} }}",
+                first, first, first
+            );
+            let start = std::time::Instant::now();
+            assert_eq!(parse_and_eval(&code).unwrap(), 0);
+            eprintln!("first block {}: {:?}", first, start.elapsed());
+        }
+    }
+
+    #[test]
+    fn test_best_fit() {
+        // Leaves a 5-word hole and then a 3-word hole. First-fit puts the
+        // 3-word block in the first hole, so the 5-word block doesn't fit.
+        let code = r#"
+            block 0 {
+                r0 = malloc(5);
+                r1 = malloc(1);
+                r2 = malloc(3);
+                r3 = malloc(1);
+                free(r0);
+                free(r2);
+                r4 = malloc(3);
+                r5 = malloc(5);
+                exit(r5);
+            }"#;
+        let config = |alloc| super::eval::Config {
+            heap_size: 11,
+            alloc,
+            ..Default::default()
+        };
+        match parse_and_eval_with(code, config(super::eval::Alloc::FirstFit)) {
+            Err(super::Error::OutOfMemory { requested: 5, .. }) => (),
+            r => panic!("expected out of memory, got {:?}", r),
+        }
+        let r = parse_and_eval_with(code, config(super::eval::Alloc::BestFit));
+        assert_eq!(r.unwrap(), 1);
+    }
+
+    #[test]
+    fn test_abort_parsing() {
+        assert_code_eq_block("block 0 { abort; }", Instr::Abort(None));
+        assert_code_eq_block("block 0 { abort(); }", Instr::Abort(None));
+        assert_code_eq_block(
+            r#"block 0 { abort("underflow"); }"#,
+            Instr::Abort(Some("underflow".to_string())),
+        );
+        let reason = r#"out of memory: "r1" \ 2"#;
+        let code = r#"block 0 { abort("out of memory: \"r1\" \\ 2"); }"#;
+        assert_code_eq_block(code, Instr::Abort(Some(reason.to_string())));
+        let printed = Instr::Abort(Some(reason.to_string())).to_string();
+        assert_eq!(printed, r#"abort("out of memory: \"r1\" \\ 2");"#);
+        match super::parser::parse(r#"block 0 { abort("a\nb"); }"#) {
+            Err(super::Error::Parse(_)) => (),
+            _ => panic!("expected a parse error"),
+        }
+    }
+
+    #[test]
+    fn test_abort_message() {
+        match parse_and_eval("block 0 { abort(); }") {
+            Err(err @ super::Error::Abort { .. }) => assert_eq!(
+                err.to_string(),
+                "block 0, line 1, column 11: called abort"
+            ),
+            r => panic!("expected an abort, got {:?}", r),
+        }
+        match parse_and_eval(r#"block 0 { abort("underflow"); }"#) {
+            Err(err @ super::Error::Abort { .. }) => assert_eq!(
+                err.to_string(),
+                "block 0, line 1, column 11: called abort: underflow"
+            ),
+            r => panic!("expected an abort, got {:?}", r),
+        }
+        match parse_and_eval(r#"block 0 { abort("out of memory"); }"#) {
+            Err(err @ super::Error::Abort { .. }) => assert_eq!(
+                err.to_string(),
+                "block 0, line 1, column 11: called abort: out of memory"
+            ),
+            r => panic!("expected an abort, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_assert_parsing() {
+        assert_code_eq_block(
+            "block 0 { assert(r3); exit(0); }",
+            Instr::Assert(Val::Reg(3), Box::new(Instr::Exit(Val::Imm(0)))),
+        );
+    }
+
+    #[test]
+    fn test_assert() {
+        let code =
+            "block 0 { r0 = 6 * 7; r1 = r0 == 42; assert(r1); exit(r0); }";
+        assert_eq!(parse_and_eval(code).unwrap(), 42);
+        let code = "block 0 {\n  r0 = 6 * 7;\n  r1 = r0 == 41;\n  assert(r1);\n\
+                    exit(r0);\n}";
+        match parse_and_eval(code) {
+            Err(super::Error::Runtime(msg)) => {
+                assert_eq!(msg, "block 0, line 4, column 3: assertion failed")
+            }
+            r => panic!("expected a failed assertion, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_swap_parsing() {
+        assert_code_eq_block(
+            "block 0 { swap(r1, r2); exit(r1); }",
+            Instr::Swap(1, 2, Box::new(Instr::Exit(Val::Reg(1)))),
+        );
+    }
+
+    #[test]
+    fn test_swap() {
+        let code = "block 0 { r0 = 3; r1 = 4; swap(r0, r1); r0 = r0 * 10; \
+                    r0 = r0 + r1; exit(r0); }";
+        assert_eq!(parse_and_eval(code).unwrap(), 43);
+        let code = "block 0 { r0 = 3; swap(r0, r0); exit(r0); }";
+        assert_eq!(parse_and_eval(code).unwrap(), 3);
+        let code = "block 0 { swap(r0, r10); exit(r0); }";
+        assert!(parse_and_eval(code).is_err());
+    }
+
+    #[test]
+    fn test_negate_parsing() {
+        assert_code_eq_block(
+            "block 0 { r1 = -5; exit(r1); }",
+            Instr::Copy(1, Val::Imm(-5), Box::new(Instr::Exit(Val::Reg(1)))),
+        );
+        assert_code_eq_block(
+            "block 0 { r1 = -r0; exit(r1); }",
+            Instr::Op2(
+                1,
+                Op2::Sub,
+                Val::Imm(0),
+                Val::Reg(0),
+                Box::new(Instr::Exit(Val::Reg(1))),
+            ),
+        );
+    }
+
+    #[test]
+    fn test_negate() {
+        let r = parse_and_eval("block 0 { r0 = 7; r1 = -r0; exit(r1); }");
+        assert_eq!(r.unwrap(), -7);
+        let r = parse_and_eval("block 0 { r0 = -7; r1 = -r0; exit(r1); }");
+        assert_eq!(r.unwrap(), 7);
+    }
+
+    #[test]
+    fn test_empty_block_parsing() {
+        let blocks =
+            super::parser::parse("block 0 { } block 5 { exit(1); }").unwrap();
+        assert_eq!(blocks[0], (0, Instr::Goto(Val::Imm(5))));
+        match super::parser::parse("block 0 { goto(1); } block 1 { }") {
+            Err(super::Error::Usage(msg)) => {
+                assert_eq!(msg, "block 1 is empty, and no block follows it")
+            }
+            r => panic!("expected a usage error, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_empty_block_falls_through() {
+        let r = parse_and_eval(
+            r#"
+            block 0 { r0 = 3; goto(2); }
+            block 7 { r0 = r0 * 2; exit(r0); }
+            block 2 { }
+            block 4 { r0 = r0 + 1; goto(6); }
+            block 6 { }
+            "#,
+        );
+        assert_eq!(r.unwrap(), 8);
+    }
+
+    #[test]
+    fn test_call_parsing() {
+        let code = "block 0 { call(1); exit(r0); } block 1 { ret(7); }";
+        let expected_block =
+            Instr::Call(Val::Imm(1), Box::new(Instr::Exit(Val::Reg(0))));
+        assert_code_eq_block(code, expected_block);
+    }
+
+    #[test]
+    fn test_call_ret() {
+        let r = parse_and_eval(
+            r#"
+            block 0 {
+                r1 = 2;
+                call(5);
+                r0 = r0 + r1;
+                exit(r0);
+            }
+            block 5 {
+                r1 = r1 * 10;
+                call(6);
+                ret(r0);
+            }
+            block 6 {
+                ret(22);
+            }"#,
+        ).unwrap();
+        assert_eq!(r, 42);
+    }
+
+    #[test]
+    fn test_ret_without_call() {
+        match parse_and_eval("block 0 { ret(1); }") {
+            Err(super::Error::Runtime(msg)) => assert!(
+                msg.ends_with("ret with an empty call stack"),
+                "{}",
+                msg
+            ),
+            _ => panic!("expected a runtime error"),
+        }
+    }
+
+    #[test]
+    fn test_block_labels() {
+        let r = parse_and_eval(
+            r#"
+            block main {
+                r0 = 5;
+                r1 = 0;
+                goto(loop);
+            }
+            block loop {
+                ifz r0 {
+                    goto(done);
+                }
+                else {
+                    r1 = r1 + r0;
+                    r0 = r0 - 1;
+                    goto(loop);
+                }
+            }
+            block 7 {
+                abort;
+            }
+            block done {
+                exit(r1);
+            }"#,
+        ).unwrap();
+        assert_eq!(r, 15);
+        let code =
+            "block 0 { goto(x); } block 7 { exit(0); } block x { abort; }";
+        let blocks = super::tc::tc(super::parser::parse(code).unwrap());
+        assert!(blocks.unwrap().contains_key(&8));
+    }
+
+    #[test]
+    fn test_duplicate_block_ids() {
+        let code = "block 0 { goto(5); } block 5 { exit(1); } \
+                    block 5 { exit(2); } block 7 { exit(3); } \
+                    block 7 { exit(4); } block 5 { exit(5); }";
+        let blocks = super::parser::parse(code).unwrap();
+        match super::tc::tc(blocks) {
+            Err(super::Error::Usage(msg)) => {
+                assert_eq!(msg, "duplicate block IDs: 5, 7")
+            }
+            _ => panic!("expected a usage error"),
+        }
+    }
+
+    #[test]
+    fn test_missing_block_0() {
+        let blocks = super::parser::parse("block 1 { exit(1); }").unwrap();
+        match super::tc::tc(blocks) {
+            Err(super::Error::Usage(msg)) => {
+                assert_eq!(msg, "Expected block 0")
+            }
+            _ => panic!("expected a usage error"),
+        }
+    }
+
+    #[test]
+    fn test_directives() {
+        let code = "// Settings.\n#memory 2048\n#registers 16\n\n\
+                    block 0 { exit(0); }";
+        let (_, settings) = super::parser::parse_with_settings(code).unwrap();
+        assert_eq!(settings, super::parser::Settings {
+            memory: Some(2048),
+            registers: Some(16),
+        });
+        let bad = [
+            "#stack 16\nblock 0 { exit(0); }",
+            "#memory 16\n#memory 32\nblock 0 { exit(0); }",
+            "#memory\nblock 0 { exit(0); }",
+            "#memory 16 block 0 { exit(0); }",
+            "#memory 0\nblock 0 { exit(0); }",
+            "#memory 1\nblock 0 { exit(0); }",
+            "block 0 { exit(0); }\n#memory 16",
+        ];
+        for code in &bad {
+            match super::parser::parse(code) {
+                Err(super::Error::Parse(_)) => (),
+                _ => panic!("expected a parse error for {:?}", code),
+            }
+        }
+    }
+
+    #[test]
+    fn test_memory_directive() {
+        let code = "#memory 2048\n\
+                    block 0 { r0 = malloc(2000); exit(0); }";
+        let run = |cli: &[&str]| {
+            let args = super::app().get_matches_from(cli);
+            let (blocks, positions, settings) =
+                super::parse_files(&[("test", code)]).unwrap();
+            let mut opts = super::Options::default();
+            super::apply_settings(&mut opts.config, &settings, &args);
+            let mut out = Vec::new();
+            let input = &mut std::io::empty();
+            super::check_and_eval(blocks, positions, &opts, input, &mut out)
+        };
+        assert_eq!(run(&["ilvm"]).unwrap(), 0);
+        // The command line takes precedence.
+        match run(&["ilvm", "-m", "1024"]) {
+            Err(super::Error::OutOfMemory { requested: 2000, .. }) => (),
+            _ => panic!("expected an out of memory error"),
+        }
+    }
+
+    #[test]
+    fn test_bad_block_labels() {
+        let dup = "block a { exit(0); } block a { exit(1); }";
+        match super::parser::parse(dup) {
+            Err(super::Error::Usage(msg)) => {
+                assert_eq!(msg, "duplicate block label a")
+            }
+            _ => panic!("expected a usage error"),
+        }
+        match super::parser::parse("block main { goto(nowhere); }") {
+            Err(super::Error::Usage(msg)) => assert_eq!(
+                msg,
+                "line 1, column 19: undefined block label nowhere"
+            ),
+            _ => panic!("expected a usage error"),
+        }
+    }
+
+    #[test]
+    fn test_indirect_goto() {
+        let r = parse_and_eval(
+            r#"
+            block 0 {
+                r2 = 200;
+                r3 = 10;
+                goto(r3);
+            }
+            block 10 {
+                r2 = r2 + r3;
+                exit(r2);
+            }"#,
+        ).unwrap();
+        assert!(r == 210);
+    }
+
+    #[test]
+    fn test_exit_parsing() {
+        assert_code_eq_block("block 0 { exit(); }", Instr::Exit(Val::Imm(0)));
+        assert_code_eq_block("block 0 { exit(5); }", Instr::Exit(Val::Imm(5)));
+    }
+
+    #[test]
+    fn test_exit_default() {
+        assert_eq!(parse_and_eval("block 0 { exit(); }").unwrap(), 0);
+        assert_eq!(parse_and_eval("block 0 { exit(5); }").unwrap(), 5);
+        let code = "block 0 { r0 = 7; ifz r1 { exit(); } else { exit(r0); } }";
+        assert_eq!(parse_and_eval(code).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_ifz() {
+        let r = parse_and_eval(
+            r#"
+            block 0 {
+                r2 = 1;
+                ifz r2 {
+                    exit(20);
+                }
+                else {
+                    exit(30);
+                }
+            }"#,
+        ).unwrap();
+        assert!(r == 30);
+    }
+
+    #[test]
+    fn test_ifz_without_else_parsing() {
+        let code = r#"
+            block 0 {
+                ifz r0 {
+                    exit(1);
+                }
+                r1 = 2;
+                exit(r1);
+            }"#;
+        let expected_block = Instr::IfZ(Val::Reg(0),
+            Box::new(Instr::Exit(Val::Imm(1))),
+            Box::new(Instr::Copy(1, Val::Imm(2),
+            Box::new(Instr::Exit(Val::Reg(1))))));
+        assert_code_eq_block(code, expected_block);
+        // Something still has to follow the ifz, since the true branch
+        // might not run.
+        let nothing_after = "block 0 { ifz r0 { exit(0); } }";
+        assert!(super::parser::parse(nothing_after).is_err());
+    }
+
+    #[test]
+    fn test_ifz_without_else() {
+        let code = |r0| {
+            format!(
+                r#"
+                block 0 {{
+                    r0 = {};
+                    ifz r0 {{
+                        ifz r1 {{
+                            r2 = 5;
+                            goto(1);
+                        }}
+                        exit(9);
+                    }}
+                    exit(3);
+                }}
+                block 1 {{
+                    exit(r2);
+                }}"#,
+                r0
+            )
+        };
+        assert_eq!(parse_and_eval(&code(0)).unwrap(), 5);
+        assert_eq!(parse_and_eval(&code(1)).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_ifz_falls_through() {
+        let code = "block 0 { ifz r0 { r1 = 5; } exit(r1); }";
+        let blocks = super::parser::parse(code).unwrap();
+        assert_eq!(blocks, vec![
+            (0, Instr::IfZ(Val::Reg(0),
+                Box::new(Instr::Copy(1, Val::Imm(5),
+                Box::new(Instr::Goto(Val::Imm(1))))),
+                Box::new(Instr::Goto(Val::Imm(1))))),
+            (1, Instr::Exit(Val::Reg(1))),
+        ]);
+        let code = |r0, r2| {
+            format!(
+                r#"
+                block 0 {{
+                    r0 = {};
+                    r2 = {};
+                    r1 = 10;
+                    ifz r0 {{
+                        ifz r2 {{
+                            r1 = r1 + 1;
+                        }}
+                        r1 = r1 * 2;
+                    }}
+                    r1 = r1 + 100;
+                    exit(r1);
+                }}"#,
+                r0, r2
+            )
+        };
+        assert_eq!(parse_and_eval(&code(0, 0)).unwrap(), 122);
+        assert_eq!(parse_and_eval(&code(0, 1)).unwrap(), 120);
+        assert_eq!(parse_and_eval(&code(1, 0)).unwrap(), 110);
+        let in_loop = "block 0 { r0 = 4; while r0 { ifz r1 { r2 = r2 + 1; } \
+                       r0 = r0 - 1; } exit(r2); }";
+        assert_eq!(parse_and_eval(in_loop).unwrap(), 4);
+        match super::parser::parse("block 0 { ifz r0 { r1 = 5; } }") {
+            Err(super::Error::Parse(msg)) => {
+                assert!(msg.ends_with("nothing follows the ifz"))
+            }
+            _ => panic!("expected a parse error"),
+        }
+    }
+
+    #[test]
+    fn test_while() {
+        let with_while = r#"
+            block 0 {
+                r0 = 10;
+                r1 = 0;
+                while r0 {
+                    r1 = r1 + r0;
+                    r0 = r0 - 1;
+                }
+                goto(1);
+            }
+            block 1 {
+                exit(r1);
+            }"#;
+        let with_gotos = r#"
+            block 0 {
+                r0 = 10;
+                r1 = 0;
+                goto(1);
+            }
+            block 1 {
+                ifz r0 {
+                    exit(r1);
+                }
+                else {
+                    r1 = r1 + r0;
+                    r0 = r0 - 1;
+                    goto(1);
+                }
+            }"#;
+        assert_eq!(parse_and_eval(with_while).unwrap(), 55);
+        assert_eq!(parse_and_eval(with_gotos).unwrap(), 55);
+        // The loop's blocks get IDs that no other block has.
+        let blocks = super::parser::parse(with_while).unwrap();
+        let mut ids: Vec<i32> = blocks.iter().map(|(id, _)| *id).collect();
+        ids.sort();
+        assert_eq!(ids, [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_nested_while() {
+        // Sums i * j for i and j from 1 to 3, inside a branch.
+        let code = r#"
+            block 0 {
+                ifz r5 {
+                    r0 = 3;
+                    while r0 {
+                        r1 = 3;
+                        while r1 {
+                            r2 = r0 * r1;
+                            r3 = r3 + r2;
+                            r1 = r1 - 1;
+                        }
+                        r0 = r0 - 1;
+                    }
+                    exit(r3);
+                }
+                exit(-1);
+            }"#;
+        assert_eq!(parse_and_eval(code).unwrap(), 36);
+        let nothing_after = "block 0 { while r0 { r0 = r0 - 1; } }";
+        match super::parser::parse(nothing_after) {
+            Err(super::Error::Parse(msg)) => {
+                assert!(msg.ends_with("nothing follows the while loop"))
+            }
+            _ => panic!("expected a parse error"),
+        }
+        match super::parser::parse("block 0 { while r0 { exit(0); }") {
+            Err(super::Error::Parse(msg)) => {
+                assert!(msg.ends_with("while loop is not inside a block"))
+            }
+            _ => panic!("expected a parse error"),
+        }
+    }
+
+    const FAC: &str = r#"
+        block 0 {
+            r2 = 1;
+            r1 = 5;
+            goto(1);
+        }
+        block 1 {
+            ifz r1 {
+               exit(r2);
+            }
+            else {
+                r2 = r2 * r1;
+                r1 = r1 - 1;
+                goto(1);
+            }
+        }"#;
+
+    #[test]
+    fn test_fac() {
+        let r = parse_and_eval(FAC).unwrap();
+        assert!(r == 120);
+    }
+
+    #[test]
+    fn test_breakpoint_hits() {
+        use super::eval::{Io, Machine, StepResult};
+        let (blocks, positions) = super::repl_program(FAC).unwrap();
+        let mut out = Vec::new();
+        let io = Io {
+            input: &mut std::io::empty(),
+            out: &mut out,
+            trace: None,
+            dump: None,
+        };
+        let config = super::eval::Config {
+            breakpoints: vec![1],
+            ..Default::default()
+        };
+        let heap = Box::new(vec![0; 100]);
+        let mut machine =
+            Machine::new(&config, &blocks, &positions, heap, io).unwrap();
+        let mut hits = 0;
+        loop {
+            match machine.step().unwrap() {
+                StepResult::Running => (),
+                StepResult::Paused(id) => {
+                    assert_eq!(id, 1);
+                    hits += 1;
+                }
+                StepResult::Exited(n) => {
+                    assert_eq!(n, 120);
+                    break;
+                }
+            }
+        }
+        assert_eq!(hits, 6);
+    }
+
+    // Runs `code` and returns the counts of what it did.
+    fn eval_stats(code: &str) -> super::eval::Stats {
+        let blocks = super::tc::tc(super::parser::parse(code).unwrap());
+        super::eval::eval_with_state(
+            &super::eval::Config::default(),
+            blocks.unwrap(),
+            HashMap::new(),
+        ).unwrap()
+        .stats
+    }
+
+    #[test]
+    fn test_stats() {
+        let stats = eval_stats(FAC);
+        // 3 in block 0, 4 for each of the 5 iterations, and then ifz and exit.
+        assert_eq!(stats.instructions, 25);
+        assert_eq!(stats.heap_accesses, 0);
+        let stats = eval_stats(
+            r#"
+            block 0 {
+                r0 = malloc(4);
+                r1 = malloc(2);
+                *r1 = 5;
+                r2 = *r1;
+                free(r0);
+                r0 = realloc(r1, 3);
+                exit(0);
+            }"#,
+        );
+        assert_eq!(
+            super::format_stats(&stats),
+            "instructions: 7\nmalloc calls: 2\nfree calls: 1\n\
+             words allocated: 3 (peak 6)\nheap accesses: 6\n"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_json_round_trip() {
+        let blocks = super::parser::parse(FAC).unwrap();
+        let json = super::blocks_json(&blocks).unwrap();
+        let parsed: Vec<super::syntax::Block> =
+            super::serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, blocks);
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_json_format() {
+        let blocks = super::parser::parse("block 0 { r1 = r0 + 1; exit(r1); }");
+        let json = super::blocks_json(&blocks.unwrap()).unwrap();
+        let value = |s: &str| -> super::serde_json::Value {
+            super::serde_json::from_str(s).unwrap()
+        };
+        let expected = r#"[[0, {"Op2": [1, "Add", {"Reg": 0}, {"Imm": 1},
+                                 {"Exit": {"Reg": 1}}]}]]"#;
+        assert_eq!(value(&json), value(expected));
+    }
+
+    #[test]
+    fn test_dump_ast() {
+        let blocks = super::parser::parse(FAC).unwrap();
+        let dump = super::syntax::format_blocks(&blocks);
+        assert!(dump.contains("block 0 {"));
+        assert!(dump.contains("block 1 {"));
+        assert!(dump.contains("    ifz r1 {\n        exit(r2);\n    }\n"));
+        assert_eq!(super::parser::parse(&dump).unwrap(), blocks);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        // Split across blocks, since the parser recurses once per
+        // instruction.
+        let every_instr = r#"
+            block main {
+                r0 = -2147483648;
+                r1 = r0 + 1; r1 = r0 - 1; r1 = r0 * 1; r1 = r0 / 1;
+                r1 = r0 % 1; r1 = r0 == 1; r1 = r0 < 1; r1 = r0 > 1;
+                r1 = r0 <= 1; r1 = r0 >= 1; r1 = r0 != 1; r1 = r0 & 1;
+                r1 = r0 | 1; r1 = r0 ^ 1; r1 = r0 << 1; r1 = r0 >> 1;
+                r1 = min(r0, 1); r1 = max(-1, r0); r1 = pow(r0, 2);
+                r1 = abs(r0); r1 = sign(r0);
+                swap(r0, r1);
+                goto(heap);
+            }
+            block heap {
+                r2 = malloc(4);
+                r2 = calloc(4);
+                r2 = realloc(r2, 6);
+                memcpy(r2, r2, 1);
+                memset(r2, 0, 6);
+                *r2 = -5;
+                *3 = r2;
+                r3 = *r2;
+                r2[1] = r3;
+                r3 = r2[0x10];
+                r4 = fetchadd(r2, 1);
+                r5 = read();
+                print("hi");
+                print(r5);
+                print(hex(r5));
+                print(seq(0, 3));
+                print(array(r2, 2));
+                tag("t", r4);
+                capture("c", r2, 2);
+                free(r2);
+                spawn(f);
+                yield;
+                call(f);
+                ifz r0 { abort; } else { goto(f); }
+            }
+            block f {
+                ifz 0 { ret(r0); } else { abort("unreachable"); }
+            }"#;
+        for code in &[FAC, TWO_TASKS, INDEXED, every_instr] {
+            let blocks = super::parser::parse(code).unwrap();
+            let source = super::syntax::format_blocks(&blocks);
+            assert_eq!(super::parser::parse(&source).unwrap(), blocks);
+        }
+    }
+
+    #[test]
+    fn test_trace() {
+        let (blocks, positions) =
+            super::parser::parse_with_positions(FAC).unwrap();
+        let blocks = super::tc::tc(blocks).unwrap();
+        let mut trace = Vec::new();
+        let io = super::eval::Io {
+            input: &mut std::io::empty(),
+            out: &mut Vec::new(),
+            trace: Some(&mut trace),
+            dump: None,
+        };
+        let st = super::eval::eval_with_memory(
+            &super::eval::Config::default(),
+            blocks,
+            positions,
+            Box::new(vec![0; 100]),
+            io,
+        ).unwrap();
+        assert_eq!(st.exit_code, 120);
+        let trace = String::from_utf8(trace).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        // Three instructions in block 0, four for each of the five trips
+        // around block 1, and the final ifz and exit.
+        assert_eq!(lines.len(), 25);
+        assert_eq!(lines[0], "block 0: r2 = 1;");
+        assert_eq!(lines[4], "block 1: r2 = r2 * r1;");
+        assert_eq!(lines[24], "block 1: exit(r2);");
+    }
+
+    #[test]
+    fn test_malloc() {
+        let r = parse_and_eval(
+            r#"
+            block 0 {
+                r0 = malloc(10);
+                exit(r0);
+            }"#,
+        ).unwrap();
+        assert!(r == 1);
+    }
+
+    #[test]
+    fn test_error_source() {
+        use std::error::Error;
+        let err = super::Error::from(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "gone",
+        ));
+        assert_eq!(err.source().unwrap().to_string(), "gone");
+        let err = super::Error::Usage("bad".to_string());
+        assert!(err.source().is_none());
+    }
+
+    #[test]
+    fn test_malloc_zero() {
+        let r = parse_and_eval("block 0 { r0 = malloc(0); exit(r0); }");
+        assert_eq!(r.unwrap(), 0);
+    }
+
+    #[test]
+    fn test_null_dereference() {
+        let r = parse_and_eval(
+            "block 0 { r0 = malloc(0); *r0 = 1; exit(0); }",
+        );
+        match r {
+            Err(super::Error::Runtime(msg)) => {
+                assert!(msg.ends_with("null pointer dereference"), "{}", msg)
+            }
+            _ => panic!("expected a runtime error"),
+        }
+        let r = parse_and_eval("block 0 { r1 = *r0; exit(r1); }");
+        assert!(r.is_err());
+    }
+
+    #[test]
+    fn test_malloc_negative() {
+        let r = parse_and_eval("block 0 { r0 = malloc(-1); exit(r0); }");
+        match r {
+            Err(super::Error::Runtime(msg)) => {
+                assert!(msg.ends_with("malloc with negative size"), "{}", msg)
+            }
+            _ => panic!("expected a runtime error"),
+        }
+    }
+
+    #[test]
+    fn test_undefined_goto_target() {
+        let blocks = super::parser::parse(
+            "block 0 { ifz r0 { goto(99); } else { call(42); goto(99); } }",
+        ).unwrap();
+        match super::tc::tc(blocks) {
+            Err(super::Error::Usage(msg)) => {
+                assert_eq!(msg, "undefined blocks: 42, 99")
+            }
+            _ => panic!("expected a usage error"),
+        }
+        let blocks = super::parser::parse(
+            "block 0 { spawn(1); spawn(99); goto(1); } block 1 { exit(0); }",
+        ).unwrap();
+        match super::tc::tc(blocks) {
+            Err(super::Error::Usage(msg)) => {
+                assert_eq!(msg, "undefined blocks: 99")
+            }
+            _ => panic!("expected a usage error"),
+        }
+    }
+
+    #[test]
+    fn test_infinite_loop_flagged() {
+        let blocks = super::parser::parse("block 0 { goto(0); }").unwrap();
+        let blocks = super::tc::tc(blocks).unwrap();
+        assert_eq!(super::tc::infinite_loops(&blocks), vec![vec![0]]);
+    }
+
+    #[test]
+    fn test_unreachable_block_flagged() {
+        let blocks = super::parser::parse(
+            r#"
+            block 0 {
+                ifz r0 {
+                    goto(1);
+                }
+                else {
+                    call(2);
+                    exit(0);
+                }
+            }
+            block 1 { exit(1); }
+            block 2 { ret(0); }
+            block 3 { goto(1); }"#,
+        ).unwrap();
+        let blocks = super::tc::tc(blocks).unwrap();
+        assert_eq!(super::tc::unreachable_blocks(&blocks), vec![3]);
+    }
+
+    #[test]
+    fn test_indirect_goto_reaches_every_block() {
+        let blocks = super::parser::parse(
+            r#"
+            block 0 {
+                r0 = 3;
+                goto(r0);
+            }
+            block 3 { exit(0); }"#,
+        ).unwrap();
+        let blocks = super::tc::tc(blocks).unwrap();
+        assert!(super::tc::unreachable_blocks(&blocks).is_empty());
+    }
+
+    #[test]
+    fn test_uninitialized_read_flagged() {
+        let check = |code| {
+            let blocks = super::parser::parse(code).unwrap();
+            let blocks = super::tc::tc(blocks).unwrap();
+            super::tc::check_initialized(&blocks, HashSet::new())
+        };
+        match check("block 0 { r0 = 1; exit(r5); }") {
+            Err(super::Error::Usage(msg)) => {
+                assert_eq!(msg, "block 0 may read r5 before writing it")
+            }
+            _ => panic!("expected a usage error"),
+        }
+        // r2 is only written on one path into block 1.
+        let one_path = r#"
+            block 0 {
+                r1 = read();
+                ifz r1 { goto(1); } else { r2 = 5; goto(1); }
+            }
+            block 1 { exit(r2); }"#;
+        assert!(check(one_path).is_err());
+        let both_paths = r#"
+            block 0 {
+                r1 = read();
+                ifz r1 { r2 = 4; goto(1); } else { r2 = 5; goto(1); }
+            }
+            block 1 { r2 = r2 + 1; exit(r2); }"#;
+        assert!(check(both_paths).is_ok());
+    }
+
+    #[test]
+    fn test_fold_arithmetic() {
+        let code = "block 0 { r0 = 3 + 4; r1 = r0 * 2; r2 = 1 / 0; exit(r1); }";
+        let blocks = super::tc::tc(super::parser::parse(code).unwrap());
+        let mut blocks = blocks.unwrap();
+        let folded = super::opt::fold(blocks.remove(&0).unwrap());
+        // Division by zero is left to fail at runtime.
+        let expected = Instr::Copy(0, Val::Imm(7), Box::new(
+            Instr::Op2(1, Op2::Mul, Val::Reg(0), Val::Imm(2), Box::new(
+                Instr::Op2(2, Op2::Div, Val::Imm(1), Val::Imm(0), Box::new(
+                    Instr::Exit(Val::Reg(1))
+                ))
+            ))
+        ));
+        assert_eq!(folded, expected);
+    }
+
+    #[test]
+    fn test_fold_constant_ifz() {
+        let code = r#"
+            block 0 {
+                ifz 0 { ifz 5 { abort; } else { r0 = 2 - 1; exit(r0); } }
+                else { exit(2); }
+            }"#;
+        let blocks = super::tc::tc(super::parser::parse(code).unwrap());
+        let mut blocks = blocks.unwrap();
+        let folded = super::opt::fold(blocks.remove(&0).unwrap());
+        let expected = Instr::Copy(0, Val::Imm(1), Box::new(
+            Instr::Exit(Val::Reg(0))
+        ));
+        assert_eq!(folded, expected);
+    }
+
+    #[test]
+    fn test_optimize_keeps_positions() {
+        let code = "block 0 {\n  ifz 1 { exit(0); } else {\n  r0 = 1 / 0;\n\
+                    exit(r0); }\n}";
+        let (blocks, positions) =
+            super::parser::parse_with_positions(code).unwrap();
+        let blocks = super::tc::tc(blocks).unwrap();
+        let (blocks, positions) = super::opt::optimize(blocks, positions);
+        let r = super::eval::eval(
+            &super::eval::Config::default(),
+            blocks,
+            positions,
+        );
+        match r {
+            Err(err @ super::Error::DivByZero { .. }) => assert_eq!(
+                err.to_string(),
+                "block 0, line 3, column 3: division by zero"
+            ),
+            _ => panic!("expected a division by zero"),
+        }
+    }
+
+    #[test]
+    fn test_bad_store_flagged() {
+        let blocks = super::parser::parse(
+            r#"
+            block 0 {
+                r0 = 200;
+                goto(1);
+            }
+            block 1 {
+                *r0 = 42;
+                exit(0);
+            }"#,
+        ).unwrap();
+        let blocks = super::tc::tc(blocks).unwrap();
+        let entry = HashSet::new();
+        assert_eq!(super::tc::bad_stores(&blocks, entry), vec![(1, 0)]);
+    }
+
+    #[test]
+    fn test_pointer_store_not_flagged() {
+        let blocks = super::parser::parse(
+            r#"
+            block 0 {
+                r0 = malloc(2);
+                r1 = r0 + 1;
+                goto(1);
+            }
+            block 1 {
+                *r1 = 42;
+                r0[0] = 7;
+                exit(0);
+            }"#,
+        ).unwrap();
+        let blocks = super::tc::tc(blocks).unwrap();
+        assert!(super::tc::bad_stores(&blocks, HashSet::new()).is_empty());
+    }
+
+    #[test]
+    fn test_long_loop() {
+        let r = parse_and_eval(
+            r#"
+            block 0 {
+                r0 = 2_000_000;
+                r1 = 0;
+                goto(1);
+            }
+            block 1 {
+                ifz r0 {
+                    exit(r1);
+                }
+                else {
+                    r0 = r0 - 1;
+                    r1 = r1 + 1;
+                    goto(1);
+                }
+            }"#,
+        );
+        assert_eq!(r.unwrap(), 2_000_000);
+    }
+
+    #[test]
+    fn test_step_limit() {
+        let r = parse_and_eval_with(
+            "block 0 { goto(0); }",
+            super::eval::Config {
+                step_limit: Some(100),
+                ..Default::default()
+            },
+        );
+        match r {
+            Err(super::Error::Runtime(msg)) => {
+                assert!(msg.ends_with("step limit exceeded"), "{}", msg)
+            }
+            _ => panic!("expected a runtime error"),
+        }
+    }
+
+    #[test]
+    fn test_timeout() {
+        let r = parse_and_eval_with(
+            "block 0 { r0 = r0 + 1; r0 = r0 - 1; goto(0); }",
+            super::eval::Config {
+                timeout: Some(std::time::Duration::from_millis(10)),
+                ..Default::default()
+            },
+        );
+        match r {
+            Err(super::Error::Runtime(msg)) => {
+                assert!(msg.ends_with("timeout exceeded"), "{}", msg)
+            }
+            _ => panic!("expected a runtime error"),
+        }
+        assert!(super::parse_timeout("0.5").is_ok());
+        assert!(super::parse_timeout("-1").is_err());
+        assert!(super::parse_timeout("soon").is_err());
+    }
+
+    #[test]
+    fn test_conditional_loop_not_flagged() {
+        let blocks = super::parser::parse(
+            r#"
+            block 0 {
+                r1 = 5;
+                goto(1);
+            }
+            block 1 {
+                ifz r1 {
+                   exit(0);
+                }
+                else {
+                    r1 = r1 - 1;
+                    goto(1);
+                }
+            }"#,
+        ).unwrap();
+        let blocks = super::tc::tc(blocks).unwrap();
+        assert!(super::tc::infinite_loops(&blocks).is_empty());
+        // Any instruction that can fail may end the loop.
+        let failing = [
+            "assert(r0);",
+            "r1 = read();",
+            "r1 = 10 / r0;",
+            "r1 = r1 + 1;",
+            "r1 = abs(r1);",
+            "r1 = *r0;",
+            "*r0 = 1;",
+            "r1 = malloc(1);",
+            "print(array(r0, 2));",
+        ];
+        for instr in &failing {
+            let code = format!("block 0 {{ {} goto(0); }}", instr);
+            let blocks = super::parser::parse(&code).unwrap();
+            let blocks = super::tc::tc(blocks).unwrap();
+            assert!(super::tc::infinite_loops(&blocks).is_empty(), "{}", code);
+        }
+        let safe = [
+            "r1 = r0;",
+            "r1 = r0 < 5;",
+            "r1 = max(r0, 5);",
+            "r1 = sign(r0);",
+            "print(r0);",
+            "tag(\"t\", r0);",
+            "yield;",
+        ];
+        for instr in &safe {
+            let code = format!("block 0 {{ {} goto(0); }}", instr);
+            let blocks = super::parser::parse(&code).unwrap();
+            let blocks = super::tc::tc(blocks).unwrap();
+            let loops = super::tc::infinite_loops(&blocks);
+            assert_eq!(loops, vec![vec![0]], "{}", code);
+        }
+    }
+
+    #[test]
+    fn test_tag_dump() {
+        let blocks = super::parser::parse(
+            r#"
+            block 0 {
+                r0 = 4;
+                tag("total", r0);
+                r0 = r0 * 10;
+                tag("total", r0);
+                tag("seven", 7);
+                exit(0);
+            }"#,
+        ).unwrap();
+        let blocks = super::tc::tc(blocks).unwrap();
+        let config = super::eval::Config::default();
+        let (r, records) =
+            super::eval::eval_with_records(&config, blocks, HashMap::new())
+                .unwrap();
+        assert!(r == 0);
+        assert_eq!(
+            super::format_tags(&records.tags),
+            "seven = 7\ntotal = 40\n"
+        );
+    }
+
+    #[test]
+    fn test_runtime_error_position() {
+        let r = parse_and_eval(
+            r#"
+            block 0 {
+                r0 = 1;
+                goto(1);
+            }
+            block 1 {
+                ifz r0 {
+                    exit(0);
+                }
+                else {
+                    r1 = 100000;
+                      *r1 = 5;
+                    exit(1);
+                }
+            }"#,
+        );
+        match r {
+            Err(super::Error::OutOfBounds {
+                addr: 100000,
+                block: Some(1),
+                pos: Some(pos),
+            }) => {
+                assert_eq!(pos, Pos { line: 12, column: 23 })
+            }
+            _ => panic!("expected an out of bounds error"),
+        }
+    }
+
+    #[test]
+    fn test_runtime_message_position() {
+        let code = "block 0 {\n  r0 = malloc(1);\n  free(r0);\n  *r0 = 5;\n\
+                    exit(0);\n}";
+        match parse_and_eval(code) {
+            Err(super::Error::Runtime(msg)) => assert_eq!(
+                msg,
+                "block 0, line 4, column 3: use after free at 1"
+            ),
+            _ => panic!("expected a runtime error"),
+        }
+    }
+
+    #[test]
+    fn test_out_of_bounds_block() {
+        let code = "block 0 { r0 = 5000; goto(1); }\n\
+                    block 1 { r1 = 1; *r0 = r1; exit(0); }";
+        match parse_and_eval(code) {
+            Err(err @ super::Error::OutOfBounds { .. }) => assert_eq!(
+                err.to_string(),
+                "block 1, line 2, column 19: invalid address 5000"
+            ),
+            r => panic!("expected an out of bounds error, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_runtime_error_block() {
+        let code = "block 0 { r0 = malloc(2); goto(1); }\n\
+                    block 1 { r1 = r0[5]; exit(r1); }";
+        let r = parse_and_eval_with(code, super::eval::Config {
+            strict_bounds: true,
+            ..Default::default()
+        });
+        match r {
+            Err(super::Error::Runtime(msg)) => assert_eq!(
+                msg,
+                "block 1, line 2, column 11: index 5 out of bounds for block \
+                 of size 2"
+            ),
+            _ => panic!("expected a runtime error"),
+        }
+    }
+
+    #[test]
+    fn test_deny() {
+        let blocks = super::parser::parse(
+            r#"
+            block 0 {
+                r0 = malloc(2);
+                exit(r0);
+            }"#,
+        ).unwrap();
+        let blocks = super::tc::tc(blocks).unwrap();
+        let malloc = vec!["malloc".to_string()];
+        let print = vec!["print".to_string()];
+        assert!(super::tc::deny(&blocks, &malloc).is_err());
+        assert!(super::tc::deny(&blocks, &print).is_ok());
+    }
+
+    #[test]
+    fn test_capture_before_and_after_sort() {
+        let blocks = super::parser::parse(
+            r#"
+            block 0 {
+                r0 = 10;
+                *r0 = 5;
+                r1 = 11;
+                *r1 = 2;
+                capture("arr", 10, 2);
+                r2 = *r0;
+                r3 = *r1;
+                r4 = r3 < r2;
+                ifz r4 {
+                    exit(0);
+                }
+                else {
+                    *r0 = r3;
+                    *r1 = r2;
+                    capture("arr", 10, 2);
+                    exit(0);
+                }
+            }"#,
+        ).unwrap();
+        let blocks = super::tc::tc(blocks).unwrap();
+        let config = super::eval::Config::default();
+        let (_, records) =
+            super::eval::eval_with_records(&config, blocks, HashMap::new())
+                .unwrap();
+        assert_eq!(
+            super::format_captures(&records.captures),
+            "arr = [5, 2]\narr = [2, 5]\n"
+        );
+    }
+
+    #[test]
+    fn test_capture_out_of_bounds() {
+        let r = parse_and_eval(
+            r#"
+            block 0 {
+                capture("arr", 495, 10);
+                exit(0);
+            }"#,
+        );
+        assert!(r.is_err());
+    }
+
+    #[test]
+    fn test_many_small_mallocs() {
+        let r = parse_and_eval(
+            r#"
+            block 0 {
+                r0 = 40;
+                goto(1);
+            }
+            block 1 {
+                ifz r0 {
+                    exit(0);
+                }
+                else {
+                    r1 = malloc(1);
+                    *r1 = r0;
+                    r0 = r0 - 1;
+                    goto(1);
+                }
+            }"#,
+        );
+        assert_eq!(r.unwrap(), 0);
+    }
+
+    #[test]
+    fn test_register_out_of_range() {
+        let r = parse_and_eval("block 0 { r50 = 5; exit(r50); }");
+        match r {
+            Err(super::Error::Usage(msg)) => assert_eq!(
+                msg,
+                "block 0 uses r50, but there are only 10 registers"
+            ),
+            _ => panic!("expected a usage error"),
+        }
+        // Library callers that skip the checks get the error too.
+        let code = "block 0 { r50 = 5; exit(r50); }";
+        let (blocks, positions) =
+            super::parser::parse_with_positions(code).unwrap();
+        let blocks = super::tc::tc(blocks).unwrap();
+        let config = super::eval::Config {
+            num_registers: 10,
+            ..Default::default()
+        };
+        match super::eval::eval(&config, blocks, positions) {
+            Err(super::Error::Usage(msg)) => assert_eq!(
+                msg,
+                "block 0 uses r50, but there are only 10 registers"
+            ),
+            _ => panic!("expected a usage error"),
+        }
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        let r = parse_and_eval("block 0 { r0 = 1 / 0; exit(r0); }");
+        assert!(r.is_err());
+        let r = parse_and_eval("block 0 { r0 = 1 % 0; exit(r0); }");
+        assert!(r.is_err());
+    }
+
+    #[test]
+    fn test_fetchadd() {
+        let r = parse_and_eval(
+            r#"
+            block 0 {
+                r0 = malloc(1);
+                *r0 = 10;
+                r1 = fetchadd(r0, 5);
+                r2 = fetchadd(r0, r1);
+                r3 = *r0;
+                r4 = r1 * 1000;
+                r4 = r4 + r2;
+                r4 = r4 * 100;
+                r4 = r4 + r3;
+                exit(r4);
+            }"#,
+        );
+        // Old values 10 and 15; the counter ends at 25.
+        assert_eq!(r.unwrap(), 1001525);
+    }
+
+    const INDEXED: &str = r#"
+        block 0 {
+            r0 = malloc(3);
+            r0[2] = 7;
+            r1 = 2;
+            r2 = r0[r1];
+            r3 = r0[3];
+            exit(r2);
+        }"#;
+
+    #[test]
+    fn test_index_parsing() {
+        let code = r#"
+            block 0 {
+                r0[r1] = 5;
+                r2 = r0[3];
+                exit(r2);
+            }"#;
+        let expected_block = Instr::StoreIdx(0, Val::Reg(1), Val::Imm(5),
+            Box::new(Instr::LoadIdx(2, 0, Val::Imm(3),
+            Box::new(Instr::Exit(Val::Reg(2))))));
+        assert_code_eq_block(code, expected_block);
+    }
+
+    #[test]
+    fn test_index() {
+        // r0[3] is past the end of the block, but still on the heap.
+        assert!(parse_and_eval(INDEXED).unwrap() == 7);
+    }
+
+    #[test]
+    fn test_index_strict_bounds() {
+        let r = parse_and_eval_with(INDEXED, super::eval::Config {
+            strict_bounds: true,
+            ..Default::default()
+        });
+        match r {
+            Err(super::Error::Runtime(msg)) => assert!(
+                msg.ends_with("index 3 out of bounds for block of size 3"),
+                "{}",
+                msg
+            ),
+            _ => panic!("expected a runtime error"),
+        }
+    }
+
+    // Stores to the cell just past a block of two.
+    const ONE_PAST_END: &str = r#"
+        block 0 {
+            r0 = malloc(2);
+            r1 = r0 + 1;
+            *r1 = 5;
+            r1 = r1 + 1;
+            *r1 = 6;
+            exit(0);
+        }"#;
+
+    #[test]
+    fn test_store_past_end_strict() {
+        let strict = || super::eval::Config {
+            strict_bounds: true,
+            ..Default::default()
+        };
+        match parse_and_eval_with(ONE_PAST_END, strict()) {
+            Err(super::Error::Runtime(msg)) => assert!(
+                msg.ends_with(
+                    "r1 = 3 is outside the block at 1 that it was derived \
+                     from, which has 2 words"
+                ),
+                "{}",
+                msg
+            ),
+            _ => panic!("expected a runtime error"),
+        }
+        let in_bounds = "block 0 { r0 = malloc(2); r1 = r0 + 1; *r1 = 5; \
+                         r2 = *r1; exit(r2); }";
+        assert_eq!(parse_and_eval_with(in_bounds, strict()).unwrap(), 5);
+        assert_eq!(parse_and_eval(ONE_PAST_END).unwrap(), 0);
+    }
+
+    // r2 starts at the first block, but moves into the second.
+    const CROSS_BLOCKS: &str = r#"
+        block 0 {
+            r0 = malloc(2);
+            r1 = malloc(2);
+            r2 = r0 + 2;
+            *r2 = 7;
+            r3 = *r1;
+            exit(r3);
+        }"#;
+
+    #[test]
+    fn test_cross_block_strict() {
+        let strict = || super::eval::Config {
+            strict_bounds: true,
+            ..Default::default()
+        };
+        match parse_and_eval_with(CROSS_BLOCKS, strict()) {
+            Err(super::Error::Runtime(msg)) => assert!(
+                msg.ends_with(
+                    "r2 = 3 is outside the block at 1 that it was derived \
+                     from, which has 2 words"
+                ),
+                "{}",
+                msg
+            ),
+            _ => panic!("expected a runtime error"),
+        }
+        assert_eq!(parse_and_eval(CROSS_BLOCKS).unwrap(), 7);
+        // The pointer keeps its block when it goes through the heap.
+        let through_heap = "block 0 { r0 = malloc(2); r1 = malloc(2); \
+                            *r1 = r0; r2 = *r1; r2 = r2 + 2; r3 = *r2; \
+                            exit(r3); }";
+        assert!(parse_and_eval_with(through_heap, strict()).is_err());
+        // Arithmetic that stays inside the block, and a constant address
+        // inside some block, are both fine.
+        let in_bounds = "block 0 { r0 = malloc(3); r1 = r0 + 2; r1 = r1 - 1; \
+                         *r1 = 4; *3 = 1; r2 = *r1; exit(r2); }";
+        assert_eq!(parse_and_eval_with(in_bounds, strict()).unwrap(), 4);
+    }
+
+    #[test]
+    fn test_use_after_free() {
+        let r = parse_and_eval(
+            r#"
+            block 0 {
+                r0 = malloc(2);
+                *r0 = 1;
+                free(r0);
+                r1 = *r0;
+                exit(r1);
+            }"#,
+        );
+        match r {
+            Err(super::Error::Runtime(msg)) => {
+                assert!(msg.ends_with("use after free at 1"), "{}", msg)
+            }
+            _ => panic!("expected a runtime error"),
+        }
+    }
+
+    #[test]
+    fn test_double_free() {
+        let r = parse_and_eval(
+            r#"
+            block 0 {
+                r0 = malloc(2);
+                free(r0);
+                free(r0);
+                exit(0);
+            }"#,
+        );
+        match r {
+            Err(super::Error::DoubleFree {
+                addr: 1,
+                block: Some(0),
+                pos: Some(_),
+            }) => (),
+            _ => panic!("expected a double free"),
+        }
+    }
+
+    #[test]
+    fn test_calloc() {
+        let out = eval_output(
+            r#"
+            block 0 {
+                r0 = malloc(2);
+                memset(r0, 9, 2);
+                free(r0);
+                r1 = malloc(2);
+                print(array(r1, 2));
+                free(r1);
+                r2 = calloc(2);
+                print(array(r2, 2));
+                exit(0);
+            }"#,
+        );
+        assert_eq!(out.unwrap(), "[9, 9]\n[0, 0]\n");
+    }
+
+    #[test]
+    fn test_poison() {
+        let code = r#"
+            block 0 {
+                r0 = malloc(2);
+                r1 = *r0;
+                r2 = calloc(1);
+                r3 = *r2;
+                r1 = r1 + r3;
+                exit(r1);
+            }"#;
+        let poison = super::eval::Config {
+            poison: true,
+            ..Default::default()
+        };
+        let r = parse_and_eval_with(code, poison).unwrap();
+        assert_eq!(r, super::eval::POISON);
+        assert_eq!(r, 0xdeadbeefu32 as i32);
+        assert_eq!(parse_and_eval(code).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_realloc() {
+        let r = parse_and_eval(
+            r#"
+            block 0 {
+                r0 = malloc(2);
+                *r0 = 10;
+                r0[1] = 20;
+                r1 = malloc(1);
+                r2 = realloc(r0, 4);
+                r2[2] = 30;
+                r3 = r2[0];
+                r4 = r2[1];
+                r5 = r2[2];
+                r3 = r3 + r4;
+                r3 = r3 + r5;
+                exit(r3);
+            }"#,
+        );
+        assert_eq!(r.unwrap(), 60);
+        // The block moved, since the one after it was in use.
+        let r = parse_and_eval(
+            "block 0 { r0 = malloc(2); r1 = malloc(1); r2 = realloc(r0, 4); \
+             exit(r2); }",
+        );
+        assert_eq!(r.unwrap(), 4);
+    }
+
+    #[test]
+    fn test_realloc_unallocated() {
+        let r = parse_and_eval(
+            "block 0 { r0 = malloc(2); free(r0); r1 = realloc(r0, 4); \
+             exit(0); }",
+        );
+        match r {
+            Err(super::Error::Runtime(msg)) => assert!(
+                msg.ends_with("realloc of 1, which is not an allocated block"),
+                "{}",
+                msg
+            ),
+            _ => panic!("expected a runtime error"),
+        }
+    }
+
+    #[test]
+    fn test_memcpy() {
+        let out = eval_output(
+            r#"
+            block 0 {
+                r0 = malloc(3);
+                *r0 = 1;
+                r0[1] = 2;
+                r0[2] = 3;
+                r1 = malloc(3);
+                memcpy(r1, r0, 3);
+                print(array(r1, 3));
+                exit(0);
+            }"#,
+        );
+        assert_eq!(out.unwrap(), "[1, 2, 3]\n");
+    }
+
+    #[test]
+    fn test_memcpy_overlapping() {
+        let out = eval_output(
+            r#"
+            block 0 {
+                r0 = malloc(5);
+                *r0 = 1;
+                r0[1] = 2;
+                r0[2] = 3;
+                r1 = r0 + 2;
+                memcpy(r1, r0, 3);
+                print(array(r0, 5));
+                r1 = r0 + 1;
+                memcpy(r0, r1, 4);
+                print(array(r0, 5));
+                exit(0);
+            }"#,
+        );
+        assert_eq!(out.unwrap(), "[1, 2, 1, 2, 3]\n[2, 1, 2, 3, 3]\n");
+    }
+
+    #[test]
+    fn test_memcpy_out_of_bounds() {
+        let r = parse_and_eval(
+            "block 0 { r0 = malloc(2); memcpy(r0, 498, 4); exit(0); }",
+        );
+        match r {
+            Err(super::Error::OutOfBounds { addr: 500, .. }) => (),
+            r => panic!("expected an invalid address, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_memcpy_across_blocks() {
+        let cases = [
+            ("memcpy(r2, r0, 4);", "memcpy of 4 words at 1 overruns"),
+            ("memcpy(r0, r2, 4);", "memcpy of 4 words at 1 overruns"),
+            ("r3 = r2 + 2; memcpy(r1, r3, 3);", "memcpy of 3 words at 7"),
+        ];
+        for (copy, expected) in &cases {
+            let code = format!(
+                "block 0 {{ r0 = malloc(2); r1 = malloc(2); r2 = malloc(4); \
+                 r1[0] = 7; {} r3 = r2[2]; exit(r3); }}",
+                copy
+            );
+            for strict in &[false, true] {
+                let config = super::eval::Config {
+                    strict_bounds: *strict,
+                    ..Default::default()
+                };
+                match parse_and_eval_with(&code, config) {
+                    Err(super::Error::Runtime(msg)) => {
+                        assert!(msg.contains(expected), "{}", msg)
+                    }
+                    r => panic!("expected a runtime error, got {:?}", r),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_memset() {
+        let out = eval_output(
+            r#"
+            block 0 {
+                r0 = malloc(4);
+                memset(r0, 7, 3);
+                print(array(r0, 4));
+                r1 = r0 + 1;
+                memset(r1, -1, 0);
+                print(array(r0, 4));
+                exit(0);
+            }"#,
+        );
+        assert_eq!(out.unwrap(), "[7, 7, 7, 0]\n[7, 7, 7, 0]\n");
+    }
+
+    #[test]
+    fn test_memset_overrun() {
+        let r = parse_and_eval(
+            "block 0 { r0 = malloc(2); r1 = r0 + 1; memset(r1, 7, 2); \
+             exit(0); }",
+        );
+        match r {
+            Err(super::Error::Runtime(msg)) => assert!(
+                msg.ends_with(
+                    "memset of 2 words at 2 overruns the block at 1, which \
+                     has 2 words"
+                ),
+                "{}",
+                msg
+            ),
+            r => panic!("expected a runtime error, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_malloc_is_deterministic() {
+        let code = r#"
+            block 0 {
+                r0 = malloc(3);
+                r1 = malloc(2);
+                r2 = malloc(4);
+                free(r0);
+                free(r2);
+                r3 = malloc(1);
+                r4 = malloc(5);
+                free(r1);
+                r5 = malloc(4);
+                print(seq(0, 6));
+                exit(0);
+            }"#;
+        let first = eval_output(code).unwrap();
+        assert_eq!(first, "[1, 4, 6, 1, 6, 2]\n");
+        for _ in 0..10 {
+            assert_eq!(eval_output(code).unwrap(), first);
+        }
+    }
+
+    #[test]
+    fn test_out_of_memory() {
+        // The heap has 500 words, and address 0 is never allocated.
+        let r = parse_and_eval(
+            "block 0 { r0 = malloc(100); r1 = malloc(1000); exit(0); }",
+        );
+        match r {
+            Err(err @ super::Error::OutOfMemory { .. }) => assert_eq!(
+                err.to_string(),
+                "block 0, line 1, column 29: malloc(1000) is out of \
+                 memory, \
+                 with 399 words free"
+            ),
+            _ => panic!("expected an out of memory error"),
+        }
+    }
+
+    #[test]
+    fn test_indirect_goto_to_undefined_block() {
+        let r = parse_and_eval("block 0 { r0 = 9; goto(r0); }");
+        match r {
+            Err(super::Error::UndefinedBlock {
+                id: 9,
+                block: Some(0),
+                pos: Some(_),
+            }) => (),
+            _ => panic!("expected an undefined block error"),
+        }
+    }
+
+    #[test]
+    fn test_jump_targets() {
+        // Blocks 0 through 9 are dense, but block 5 is missing. Blocks 0 and
+        // 100000000 are too far apart for that.
+        for last in &[9, 100000000] {
+            let code = format!(
+                "block 0 {{ r0 = 3; goto({}); }} \
+                 block 4 {{ r1 = r1 + 1; ret(r0); }} \
+                 block {} {{ r0 = r0 - 1; ifz r0 {{ exit(r1); }} \
+                   else {{ call(4); goto({}); }} }}",
+                last, last, last
+            );
+            assert_eq!(parse_and_eval(&code).unwrap(), 2);
+            let code = code.replace("exit(r1);", "r3 = 5; goto(r3);");
+            match parse_and_eval(&code) {
+                Err(super::Error::UndefinedBlock { id: 5, .. }) => (),
+                _ => panic!("expected an undefined block error"),
+            }
+            for id in &[-1, 10, 100000001] {
+                let code = format!("block 0 {{ r0 = {}; goto(r0); }}", id);
+                match parse_and_eval(&code) {
+                    Err(super::Error::UndefinedBlock { .. }) => (),
+                    _ => panic!("expected an undefined block error"),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_free_interior_pointer() {
+        let r = parse_and_eval(
+            "block 0 { r0 = malloc(4); r1 = r0 + 2; free(r1); exit(0); }",
+        );
+        match r {
+            Err(super::Error::Runtime(msg)) => assert!(
+                msg.ends_with("free of 3, which is inside the block at 1 but \
+                               is not its base"),
+                "{}",
+                msg
+            ),
+            _ => panic!("expected a runtime error"),
+        }
+    }
+
+    #[test]
+    fn test_free_wild_pointer() {
+        let r = parse_and_eval("block 0 { r0 = 300; free(r0); exit(0); }");
+        match r {
+            Err(super::Error::Runtime(msg)) => assert!(
+                msg.ends_with("free of 300, which was never allocated"),
+                "{}",
+                msg
+            ),
+            _ => panic!("expected a runtime error"),
+        }
+    }
+
+    #[test]
+    fn test_json_result() {
+        let r = parse_and_eval("block 0 { exit(7); }");
+        assert_eq!(
+            super::json_result(&r, "7\n"),
+            r#"{"status": "exit", "code": 7, "message": null, "output": "7\n"}"#
+        );
+        let r = parse_and_eval("block 0 { r0 = 1000; *r0 = \"a\"; }");
+        let json = super::json_result(&r, "");
+        assert!(json.starts_with(r#"{"status": "error""#));
+        let r: Result<i32, super::Error> =
+            Err(super::Error::Runtime("say \"hi\"\n".to_string()));
+        let expected = r#"{"status": "error", "code": 1, "#.to_string()
+            + r#""message": "say \"hi\"\n", "output": ""}"#;
+        assert_eq!(super::json_result(&r, ""), expected);
+        let r = parse_and_eval("block 0 { abort; }");
+        let json = super::json_result(&r, "");
+        assert!(json.starts_with(r#"{"status": "abort""#));
+    }
+
+    #[test]
+    fn test_gen_test() {
+        let code = "block 0 {\n    r0 = read();\n    print(r0);\n    \
+                    exit(42);\n}\n";
+        let config = super::eval::Config {
+            strict_bounds: true,
+            step_limit: Some(100),
+            ..Default::default()
+        };
+        let (r, out) = parse_and_eval_io(code, "7\n", config.clone()).unwrap();
+        let run = super::Run {
+            input: "7\n",
+            output: &out,
+            result: r,
+        };
+        let test = super::gen_test("answer-1", code, &config, &run);
+        assert!(test.contains("fn test_answer_1() {"));
+        assert!(test.contains("assert_eq!(r, 42);"));
+        assert!(test.contains("assert_eq!(out, \"7\\n\");"), "{}", test);
+        assert!(test.contains("strict_bounds: true,"));
+        assert!(test.contains("step_limit: Some(100),"));
+        let start = test.find("r#\"").unwrap() + 3;
+        let end = test.find("\"#").unwrap();
+        assert!(super::parser::parse(&test[start..end]).is_ok());
+    }
+
+    #[test]
+    fn test_args() {
+        let r = parse_and_eval_with(
+            r#"
+            block 0 {
+                r2 = 0;
+                goto(1);
+            }
+            block 1 {
+                ifz r0 {
+                    exit(r2);
+                }
+                else {
+                    r0 = r0 - 1;
+                    r3 = r1[r0];
+                    r2 = r2 + r3;
+                    goto(1);
+                }
+            }"#,
+            super::eval::Config {
+                args: vec![3, 40, 500],
+                ..Default::default()
+            },
+        );
+        assert_eq!(r.unwrap(), 543);
+    }
+
+    #[test]
+    fn test_args_exceed_heap() {
+        let r = parse_and_eval_with(
+            "block 0 { exit(r0); }",
+            super::eval::Config {
+                heap_size: 4,
+                args: vec![1, 2, 3, 4],
+                ..Default::default()
+            },
+        );
+        assert!(r.is_err());
+    }
+
+    // A heap that fails every read of one address.
+    struct FaultyMemory {
+        cells: Vec<i32>,
+        bad_addr: usize,
+    }
+
+    impl super::eval::Memory for FaultyMemory {
+        fn read(&mut self, addr: usize) -> Result<i32, String> {
+            if addr == self.bad_addr {
+                return Err(format!("injected fault at {}", addr));
+            }
+            self.cells.read(addr)
+        }
+
+        fn write(&mut self, addr: usize, val: i32) -> Result<(), String> {
+            self.cells.write(addr, val)
+        }
+
+        fn len(&self) -> usize {
+            self.cells.len()
+        }
+    }
+
+    #[test]
+    fn test_memory_fault() {
+        let code = "block 0 {\n r0 = 5;\n *r0 = 1;\n r1 = *r0;\n exit(r1);\n}";
+        let (blocks, positions) =
+            super::parser::parse_with_positions(code).unwrap();
+        let blocks = super::tc::tc(blocks).unwrap();
+        let heap = FaultyMemory { cells: vec![0; 100], bad_addr: 5 };
+        let io = super::eval::Io {
+            input: &mut std::io::empty(),
+            out: &mut Vec::new(),
+            trace: None,
+            dump: None,
+        };
+        let r = super::eval::eval_with_memory(
+            &super::eval::Config::default(),
+            blocks,
+            positions,
+            Box::new(heap),
+            io,
+        );
+        match r {
+            Err(super::Error::Runtime(msg)) => assert_eq!(
+                msg,
+                "block 0, line 4, column 2: injected fault at 5"
+            ),
+            _ => panic!("expected a runtime error"),
+        }
+    }
+
+    #[test]
+    fn test_empty_memory() {
+        for len in 0..2 {
+            let (blocks, positions) =
+                super::parser::parse_with_positions("block 0 { exit(0); }")
+                    .unwrap();
+            let blocks = super::tc::tc(blocks).unwrap();
+            let heap = FaultyMemory { cells: vec![0; len], bad_addr: 0 };
+            let io = super::eval::Io {
+                input: &mut std::io::empty(),
+                out: &mut Vec::new(),
+                trace: None,
+                dump: None,
+            };
+            let r = super::eval::eval_with_memory(
+                &super::eval::Config::default(),
+                blocks,
+                positions,
+                Box::new(heap),
+                io,
+            );
+            match r {
+                Err(super::Error::Usage(msg)) => assert_eq!(
+                    msg,
+                    format!(
+                        "a heap of {} words is too small, since address 0 \
+                         is never allocated",
+                        len
+                    )
+                ),
+                _ => panic!("expected a usage error"),
+            }
+        }
+    }
+
+    // Two tasks take turns appending their ID to a log at heap[3..], then
+    // bump the count of finished tasks at heap[1]. Block 0 waits for both
+    // and exits with the log read as decimal digits.
+    const TWO_TASKS: &str = r#"
+        block 0 {
+            spawn(10);
+            spawn(20);
+            goto(1);
+        }
+        block 1 {
+            yield;
+            r0 = 1;
+            r0 = *r0;
+            r0 = r0 == 2;
+            ifz r0 {
+                goto(1);
+            }
+            else {
+                r1 = 0;
+                r2 = 0;
+                goto(2);
+            }
+        }
+        block 2 {
+            r3 = r2 < 6;
+            ifz r3 {
+                exit(r1);
+            }
+            else {
+                r4 = r2 + 3;
+                r5 = *r4;
+                r1 = r1 * 10;
+                r1 = r1 + r5;
+                r2 = r2 + 1;
+                goto(2);
+            }
+        }
+        block 10 {
+            r7 = 1;
+            r0 = 3;
+            goto(11);
+        }
+        block 20 {
+            r7 = 2;
+            r0 = 3;
+            goto(11);
+        }
+        block 11 {
+            ifz r0 {
+                r1 = 1;
+                r2 = *r1;
+                r2 = r2 + 1;
+                *r1 = r2;
+                exit(0);
+            }
+            else {
+                r1 = 2;
+                r2 = *r1;
+                r3 = r2 + 3;
+                *r3 = r7;
+                r2 = r2 + 1;
+                *r1 = r2;
+                r0 = r0 - 1;
+                yield;
+                goto(11);
+            }
+        }"#;
+
+    #[test]
+    fn test_coroutines_interleave() {
+        let r = parse_and_eval_with(TWO_TASKS, super::eval::Config {
+            coroutines: true,
+            ..Default::default()
+        }).unwrap();
+        assert_eq!(r, 121212);
+    }
+
+    #[test]
+    fn test_spawn_requires_coroutines() {
+        assert!(parse_and_eval(TWO_TASKS).is_err());
+    }
+
+    #[test]
+    fn test_repl_session() {
+        let session = "step\n\
+                       block 0 {\n\
+                       r0 = 6;\n\
+                       r1 = malloc(2);\n\
+                       *r1 = r0;\n\
+                       print(r0);\n\
+                       exit(r0);\n\
+                       }\n\
+                       step\n\
+                       step\n\
+                       regs\n\
+                       step\n\
+                       heap\n\
+                       run\n\
+                       reset\n\
+                       regs\n";
+        let mut out = Vec::new();
+        super::repl(
+            "",
+            &mut session.as_bytes(),
+            &mut out,
+            &super::eval::Config {
+                num_registers: 2,
+                ..Default::default()
+            },
+        ).unwrap();
+        let expected = "> Expected block 0\n\
+                        > . . . . . . ok\n\
+                        > in block 0\n\
+                        > in block 0\n\
+                        > r0 = 6\nr1 = 1\n\
+                        > in block 0\n\
+                        > 1: [6, 0]\n\
+                        > 6\nexited with 6\n\
+                        > restarted\n\
+                        > r0 = 0\nr1 = 0\n\
+                        > ";
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
 }
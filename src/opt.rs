@@ -0,0 +1,117 @@
+use eval::{eval_op1, eval_op2, Overflow};
+use std::collections::HashMap;
+use syntax::{Instr, Pos, SourceMap, Val};
+
+// Folds arithmetic on constants and `ifz` on a constant. Arithmetic that would
+// fail at runtime, such as division by zero, is left alone so that it still
+// fails.
+pub fn fold(instr: Instr) -> Instr {
+    fold_at(instr, &[], &mut Vec::new())
+}
+
+// Like `fold`, but `positions` are the source positions of the instructions in
+// `instr`, in pre-order, and the positions of the instructions that remain go
+// to `kept`.
+fn fold_at(instr: Instr, positions: &[Pos], kept: &mut Vec<Pos>) -> Instr {
+    let after = positions.get(1..).unwrap_or(&[]);
+    let instr = match instr {
+        Instr::IfZ(v, true_part, false_part) => {
+            let split = true_part.preorder().len().min(after.len());
+            let (true_pos, false_pos) = after.split_at(split);
+            return match v {
+                Val::Imm(0) => fold_at(*true_part, true_pos, kept),
+                Val::Imm(_) => fold_at(*false_part, false_pos, kept),
+                Val::Reg(r) => {
+                    kept.extend(positions.first());
+                    let true_part = fold_at(*true_part, true_pos, kept);
+                    let false_part = fold_at(*false_part, false_pos, kept);
+                    Instr::IfZ(
+                        Val::Reg(r),
+                        Box::new(true_part),
+                        Box::new(false_part),
+                    )
+                }
+            };
+        }
+        Instr::Op2(r, op, Val::Imm(m), Val::Imm(n), rest) => {
+            match eval_op2(&op, Overflow::Checked, m, n) {
+                Ok(k) => Instr::Copy(r, Val::Imm(k), rest),
+                Err(_) => Instr::Op2(r, op, Val::Imm(m), Val::Imm(n), rest),
+            }
+        }
+        Instr::Op1(r, op, Val::Imm(n), rest) => {
+            match eval_op1(&op, Overflow::Checked, n) {
+                Ok(k) => Instr::Copy(r, Val::Imm(k), rest),
+                Err(_) => Instr::Op1(r, op, Val::Imm(n), rest),
+            }
+        }
+        instr => instr,
+    };
+    kept.extend(positions.first());
+    let mut fold_rest = |rest: Box<Instr>| {
+        Box::new(fold_at(*rest, after, kept))
+    };
+    match instr {
+        Instr::Goto(_)
+        | Instr::Exit(_)
+        | Instr::Abort(_)
+        | Instr::Ret(_)
+        | Instr::IfZ(..) => instr,
+        Instr::Op2(r, op, v1, v2, rest) => {
+            Instr::Op2(r, op, v1, v2, fold_rest(rest))
+        }
+        Instr::Op1(r, op, v, rest) => Instr::Op1(r, op, v, fold_rest(rest)),
+        Instr::Copy(r, v, rest) => Instr::Copy(r, v, fold_rest(rest)),
+        Instr::Swap(a, b, rest) => Instr::Swap(a, b, fold_rest(rest)),
+        Instr::Load(r, v, rest) => Instr::Load(r, v, fold_rest(rest)),
+        Instr::Store(a, v, rest) => Instr::Store(a, v, fold_rest(rest)),
+        Instr::LoadIdx(r, a, i, rest) => {
+            Instr::LoadIdx(r, a, i, fold_rest(rest))
+        }
+        Instr::StoreIdx(a, i, v, rest) => {
+            Instr::StoreIdx(a, i, v, fold_rest(rest))
+        }
+        Instr::Malloc(r, v, rest) => Instr::Malloc(r, v, fold_rest(rest)),
+        Instr::Calloc(r, v, rest) => Instr::Calloc(r, v, fold_rest(rest)),
+        Instr::Realloc(r, p, v, rest) => {
+            Instr::Realloc(r, p, v, fold_rest(rest))
+        }
+        Instr::Print(p, rest) => Instr::Print(p, fold_rest(rest)),
+        Instr::Free(r, rest) => Instr::Free(r, fold_rest(rest)),
+        Instr::Tag(name, v, rest) => Instr::Tag(name, v, fold_rest(rest)),
+        Instr::Capture(name, v1, v2, rest) => {
+            Instr::Capture(name, v1, v2, fold_rest(rest))
+        }
+        Instr::Assert(v, rest) => Instr::Assert(v, fold_rest(rest)),
+        Instr::Memcpy(v1, v2, v3, rest) => {
+            Instr::Memcpy(v1, v2, v3, fold_rest(rest))
+        }
+        Instr::Memset(v1, v2, v3, rest) => {
+            Instr::Memset(v1, v2, v3, fold_rest(rest))
+        }
+        Instr::Spawn(v, rest) => Instr::Spawn(v, fold_rest(rest)),
+        Instr::Yield(rest) => Instr::Yield(fold_rest(rest)),
+        Instr::FetchAdd(r, p, v, rest) => {
+            Instr::FetchAdd(r, p, v, fold_rest(rest))
+        }
+        Instr::Read(r, rest) => Instr::Read(r, fold_rest(rest)),
+        Instr::Call(v, rest) => Instr::Call(v, fold_rest(rest)),
+    }
+}
+
+// Folds every block, and updates `positions` to match.
+pub fn optimize(
+    blocks: HashMap<i32, Instr>,
+    mut positions: SourceMap,
+) -> (HashMap<i32, Instr>, SourceMap) {
+    let mut folded = HashMap::new();
+    for (id, instr) in blocks {
+        let old = positions.remove(&id).unwrap_or_default();
+        let mut kept = Vec::new();
+        folded.insert(id, fold_at(instr, &old, &mut kept));
+        if !old.is_empty() {
+            positions.insert(id, kept);
+        }
+    }
+    (folded, positions)
+}
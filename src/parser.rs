@@ -2,15 +2,21 @@ extern crate combine;
 use syntax::*;
 
 use combine::error::ParseError;
-use combine::parser::char::{char, digit, spaces, string,alpha_num};
+use combine::parser::char::{alpha_num, char, digit, letter, space, string};
 use combine::stream::easy;
+use combine::stream::state::{SourcePosition, State};
 use combine::stream::Stream;
 use combine::{
-    attempt, between, eof, many1, optional, satisfy_map, sep_end_by, token,
-    Parser,
+    attempt, between, choice, eof, many, many1, not_followed_by, one_of,
+    optional, position, satisfy, satisfy_map, sep_end_by, skip_many,
+    skip_many1, token, Parser,
 };
 use error::Error;
+use eval::MIN_HEAP_SIZE;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fmt;
+use std::rc::Rc;
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Tok {
@@ -18,23 +24,49 @@ pub enum Tok {
     RBrace,
     LParen,
     RParen,
+    LBracket,
+    RBracket,
     Ifz,
     Else,
+    While,
     Semi,
     Equal,
     Goto,
     Abort,
     Exit,
     Malloc,
+    Calloc,
+    Realloc,
     Print,
+    Tag,
+    Capture,
+    Memcpy,
+    Memset,
+    Assert,
+    Swap,
+    Spawn,
+    Yield,
+    FetchAdd,
+    Read,
+    Call,
+    Ret,
     Array,
+    Seq,
+    Hex,
     Comma,
     Free,
     Block,
     Op2(Op2),
-    Int32(i32),
+    // An operator that is written as a call, e.g. min(a, b).
+    OpCall(Op2),
+    Op1(Op1),
+    // An integer literal, which is at most 2^31 so that `-` can make i32::MIN.
+    Int(i64),
     Reg(usize),
+    // A quoted name.
     Id(String),
+    // A block label, which `resolve_labels` replaces with the block's ID.
+    Label(String),
     Eof,
 }
 
@@ -45,56 +77,505 @@ impl fmt::Display for Tok {
     }
 }
 
-fn lex(s: &str) -> Result<Vec<Tok>, easy::ParseError<&str>> {
-    let tok = string("{")
-        .map(|_x| Tok::LBrace)
-        .or(string("}").map(|_x| Tok::RBrace))
-        .or(string("(").map(|_x| Tok::LParen))
-        .or(string(")").map(|_x| Tok::RParen))
-        .or(string("ifz").map(|_x| Tok::Ifz))
-        .or(string("goto").map(|_x| Tok::Goto))
-        .or(char('a').with(
-            string("bort").map(|_x| Tok::Abort)
-            .or(string("rray").map(|_x| Tok::Array))
-        ))
-        .or(attempt(string("else")).map(|_x| Tok::Else))
-        .or(string("exit").map(|_x| Tok::Exit))
-        .or(string("malloc").map(|_x| Tok::Malloc))
-        .or(string("free").map(|_x| Tok::Free))
-        .or(string("block").map(|_x| Tok::Block))
-        .or(string("print").map(|_x| Tok::Print))
-        .or(string(",").map(|_x| Tok::Comma))
-        .or(string(";").map(|_x| Tok::Semi))
-        .or(attempt(string("==")).map(|_x| Tok::Op2(Op2::Eq)))
-        .or(string("=").map(|_x| Tok::Equal))
-        .or(string("+").map(|_x| Tok::Op2(Op2::Add)))
-        .or(string("-").map(|_x| Tok::Op2(Op2::Sub)))
-        .or(string("*").map(|_x| Tok::Op2(Op2::Mul)))
-        .or(string("/").map(|_x| Tok::Op2(Op2::Div)))
-        .or(string("%").map(|_x| Tok::Op2(Op2::Mod)))
-        .or(string("<").map(|_x| Tok::Op2(Op2::LT)))
-        .or((optional(char('-').or(char('+'))), many1(digit())).map(
-            |(sign, digits): (Option<char>, String)| {
-                let n = digits.parse::<i32>().unwrap();
-                match sign {
-                    Some('-') => Tok::Int32(-n),
-                    _ => Tok::Int32(n),
+type LexError<'a> = easy::ParseError<State<&'a str, SourcePosition>>;
+
+// Defaults that a program sets with directive lines at the top of its file,
+// such as `#memory 2048`. Options on the command line override them.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Settings {
+    // The most words that the heap may grow to.
+    pub memory: Option<usize>,
+    pub registers: Option<usize>,
+}
+
+impl Settings {
+    // Adds the directives in `other`, which must agree with these.
+    fn merge(&mut self, other: Settings) -> Result<(), String> {
+        let fields = [
+            ("memory", &mut self.memory, other.memory),
+            ("registers", &mut self.registers, other.registers),
+        ];
+        for (name, mine, theirs) in fields {
+            match (*mine, theirs) {
+                (Some(m), Some(n)) if m != n => {
+                    return Err(format!(
+                        "conflicting #{} directives, {} and {}",
+                        name, m, n
+                    ))
+                }
+                (_, Some(n)) => *mine = Some(n),
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+parser!{
+    // A `/* ... */` comment, which may contain other block comments.
+    fn block_comment[I]()(I) -> ()
+    where [I: Stream<Item = char>]
+    {
+        let text = choice((
+            block_comment(),
+            attempt(char('*').skip(not_followed_by(char('/')))).map(|_x| ()),
+            attempt(char('/').skip(not_followed_by(char('*')))).map(|_x| ()),
+            satisfy(|c| c != '*' && c != '/').map(|_x| ()),
+        ));
+        attempt(string("/*"))
+            .with(skip_many(text))
+            .skip(string("*/").message("unterminated block comment"))
+    }
+}
+
+// Skips whitespace and comments.
+fn ws<I>() -> impl Parser<Input = I, Output = ()>
+where
+    I: Stream<Item = char>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    let line_comment =
+        attempt(string("//")).with(skip_many(satisfy(|c| c != '\n')));
+    skip_many(choice((skip_many1(space()), line_comment, block_comment())))
+}
+
+fn keyword(word: &str) -> Option<Tok> {
+    let tok = match word {
+        "ifz" => Tok::Ifz,
+        "else" => Tok::Else,
+        "goto" => Tok::Goto,
+        "abort" => Tok::Abort,
+        "exit" => Tok::Exit,
+        "malloc" => Tok::Malloc,
+        "calloc" => Tok::Calloc,
+        "realloc" => Tok::Realloc,
+        "free" => Tok::Free,
+        "fetchadd" => Tok::FetchAdd,
+        "read" => Tok::Read,
+        "print" => Tok::Print,
+        "array" => Tok::Array,
+        "seq" => Tok::Seq,
+        "hex" => Tok::Hex,
+        "tag" => Tok::Tag,
+        "capture" => Tok::Capture,
+        "memcpy" => Tok::Memcpy,
+        "memset" => Tok::Memset,
+        "assert" => Tok::Assert,
+        "swap" => Tok::Swap,
+        "spawn" => Tok::Spawn,
+        "yield" => Tok::Yield,
+        "call" => Tok::Call,
+        "ret" => Tok::Ret,
+        "block" => Tok::Block,
+        "while" => Tok::While,
+        "min" => Tok::OpCall(Op2::Min),
+        "max" => Tok::OpCall(Op2::Max),
+        "pow" => Tok::OpCall(Op2::Pow),
+        "abs" => Tok::Op1(Op1::Abs),
+        "sign" => Tok::Op1(Op1::Sign),
+        _ => return None,
+    };
+    Some(tok)
+}
+
+// The index of the `}` that closes the innermost brace that is open at
+// `from`, if there is one.
+fn closing_brace(tokens: &[(Pos, Tok)], from: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (i, (_, tok)) in tokens.iter().enumerate().skip(from) {
+        match tok {
+            Tok::LBrace => depth += 1,
+            Tok::RBrace if depth == 0 => return Some(i),
+            Tok::RBrace => depth -= 1,
+            _ => (),
+        }
+    }
+    None
+}
+
+// Rewrites each `while v { body } rest` in `files` into a block that tests
+// `v`, a block that runs `body` and then the test again, and a block that
+// runs `rest`, which is the rest of the enclosing block or branch. The new
+// blocks have labels that no source can spell, so that `resolve_labels`
+// gives them fresh IDs. Errors come with the index of the file they are in.
+fn lower_whiles(files: &mut [Vec<(Pos, Tok)>]) -> Result<(), (usize, Error)> {
+    for (i, tokens) in files.iter_mut().enumerate() {
+        let mut count = 0;
+        while let Some(start) =
+            tokens.iter().position(|(_, tok)| *tok == Tok::While)
+        {
+            let pos = tokens[start].0;
+            let error = |msg| (i, Error::Parse(format!("{}: {}", pos, msg)));
+            let open = tokens[start..]
+                .iter()
+                .position(|(_, tok)| *tok == Tok::LBrace)
+                .map(|n| start + n)
+                .ok_or_else(|| error("while loop has no body"))?;
+            let close = closing_brace(tokens, open + 1)
+                .ok_or_else(|| error("while loop has no end"))?;
+            let end = closing_brace(tokens, close + 1)
+                .ok_or_else(|| error("while loop is not inside a block"))?;
+            if end == close + 1 {
+                return Err(error("nothing follows the while loop"));
+            }
+            let label = |part| {
+                Tok::Label(format!("while {} {} {}", i, count, part))
+            };
+            let head = label("head");
+            let goto_head = || {
+                let target = vec![Tok::LParen, head.clone(), Tok::RParen];
+                [vec![Tok::Goto], target, vec![Tok::Semi]].concat()
+            };
+            let mut test = vec![Tok::Block, head.clone(), Tok::LBrace];
+            test.push(Tok::Ifz);
+            test.extend(tokens[start + 1..open].iter().map(|t| t.1.clone()));
+            test.extend(vec![Tok::LBrace, Tok::Goto, Tok::LParen]);
+            test.extend(vec![label("exit"), Tok::RParen, Tok::Semi]);
+            test.extend(vec![Tok::RBrace, Tok::Else, Tok::LBrace, Tok::Goto]);
+            test.extend(vec![Tok::LParen, label("body"), Tok::RParen]);
+            test.extend(vec![Tok::Semi, Tok::RBrace, Tok::RBrace]);
+            test.extend(vec![Tok::Block, label("body"), Tok::LBrace]);
+            let mut blocks: Vec<(Pos, Tok)> =
+                test.into_iter().map(|tok| (pos, tok)).collect();
+            blocks.extend_from_slice(&tokens[open + 1..close]);
+            let mut between = goto_head();
+            between.extend(vec![Tok::RBrace, Tok::Block, label("exit")]);
+            between.push(Tok::LBrace);
+            blocks.extend(between.into_iter().map(|tok| (pos, tok)));
+            blocks.extend_from_slice(&tokens[close + 1..end]);
+            blocks.push((pos, Tok::RBrace));
+            // The last token is Eof.
+            let eof = tokens.len() - 1;
+            tokens.splice(eof..eof, blocks);
+            let jump = goto_head().into_iter().map(|tok| (pos, tok));
+            tokens.splice(start..end, jump);
+            count += 1;
+        }
+    }
+    Ok(())
+}
+
+// Whether the instructions in `tokens` end with `goto`, `exit`, `abort`, `ret`,
+// or an `ifz` with an `else`, rather than running off the end.
+fn ends_branch(tokens: &[(Pos, Tok)]) -> bool {
+    // The last instruction starts after a `;` or a `}` that is not followed by
+    // `else`.
+    let mut depth = 0;
+    let mut last = 0;
+    for (i, (_, tok)) in tokens.iter().enumerate() {
+        let ends_instr = match tok {
+            Tok::LBrace => {
+                depth += 1;
+                false
+            }
+            Tok::RBrace => {
+                depth -= 1;
+                depth == 0
+                    && tokens.get(i + 1).map(|t| &t.1) != Some(&Tok::Else)
+            }
+            Tok::Semi => depth == 0,
+            _ => false,
+        };
+        if ends_instr && i + 1 < tokens.len() {
+            last = i + 1;
+        }
+    }
+    match tokens.get(last).map(|t| &t.1) {
+        Some(Tok::Goto)
+        | Some(Tok::Exit)
+        | Some(Tok::Abort)
+        | Some(Tok::Ret) => true,
+        Some(Tok::Ifz) => {
+            let mut depth = 0;
+            tokens[last..].iter().any(|(_, tok)| {
+                match tok {
+                    Tok::LBrace => depth += 1,
+                    Tok::RBrace => depth -= 1,
+                    _ => (),
+                }
+                depth == 0 && *tok == Tok::Else
+            })
+        }
+        _ => false,
+    }
+}
+
+// Gives each `ifz v { body } rest` in `files` that has no `else` one. If
+// `body` ends the branch, `rest` becomes the else branch. Otherwise, both
+// branches go to a new block that runs `rest`, which is the rest of the
+// enclosing block or branch, so that `body` falls through to it. Like the
+// blocks from `lower_whiles`, the new blocks have labels that no source can
+// spell. Errors come with the index of the file they are in.
+fn lower_ifzs(files: &mut [Vec<(Pos, Tok)>]) -> Result<(), (usize, Error)> {
+    for (i, tokens) in files.iter_mut().enumerate() {
+        let mut count = 0;
+        let mut from = 0;
+        while let Some(start) = tokens[from..]
+            .iter()
+            .position(|(_, tok)| *tok == Tok::Ifz)
+            .map(|n| from + n)
+        {
+            from = start + 1;
+            let pos = tokens[start].0;
+            // The parser reports an ifz that is malformed.
+            let open = match tokens[from..].iter().position(|(_, tok)| {
+                *tok == Tok::LBrace || *tok == Tok::RBrace || *tok == Tok::Semi
+            }) {
+                Some(n) if tokens[from + n].1 == Tok::LBrace => from + n,
+                _ => continue,
+            };
+            let close = match closing_brace(tokens, open + 1) {
+                Some(close) => close,
+                None => continue,
+            };
+            let end = match closing_brace(tokens, close + 1) {
+                Some(end) => end,
+                None => continue,
+            };
+            if tokens[close + 1].1 == Tok::Else {
+                continue;
+            }
+            if end == close + 1 {
+                let msg = format!("{}: nothing follows the ifz", pos);
+                return Err((i, Error::Parse(msg)));
+            }
+            if ends_branch(&tokens[open + 1..close]) {
+                tokens.insert(end, (pos, Tok::RBrace));
+                let els = vec![(pos, Tok::Else), (pos, Tok::LBrace)];
+                tokens.splice(close + 1..close + 1, els);
+                continue;
+            }
+            let join = Tok::Label(format!("ifz {} {} join", i, count));
+            let goto_join = vec![
+                Tok::Goto,
+                Tok::LParen,
+                join.clone(),
+                Tok::RParen,
+                Tok::Semi,
+            ];
+            let mut block: Vec<(Pos, Tok)> = vec![Tok::Block, join, Tok::LBrace]
+                .into_iter()
+                .map(|tok| (pos, tok))
+                .collect();
+            block.extend_from_slice(&tokens[close + 1..end]);
+            block.push((pos, Tok::RBrace));
+            // The last token is Eof.
+            let eof = tokens.len() - 1;
+            tokens.splice(eof..eof, block);
+            let mut branches = goto_join.clone();
+            branches.extend(vec![Tok::RBrace, Tok::Else, Tok::LBrace]);
+            branches.extend(goto_join);
+            branches.push(Tok::RBrace);
+            let branches = branches.into_iter().map(|tok| (pos, tok));
+            tokens.splice(close..end, branches);
+            count += 1;
+        }
+    }
+    Ok(())
+}
+
+// Replaces every block label in `files` with the ID of the block it names,
+// which may be in any of the files. The block labeled `main` is block 0, where
+// the program starts. Other labeled blocks are numbered in order, after the
+// largest numeric ID. Errors come with the index of the file they are in.
+fn resolve_labels(files: &mut [Vec<(Pos, Tok)>]) -> Result<(), (usize, Error)> {
+    let mut next = 1;
+    for pair in files.iter().flat_map(|tokens| tokens.windows(2)) {
+        if let (Tok::Block, Tok::Int(n)) = (&pair[0].1, &pair[1].1) {
+            next = next.max((*n as i32).saturating_add(1));
+        }
+    }
+    let mut ids = HashMap::new();
+    for (i, tokens) in files.iter().enumerate() {
+        for pair in tokens.windows(2) {
+            if let (Tok::Block, Tok::Label(name)) = (&pair[0].1, &pair[1].1) {
+                let id = if name == "main" { 0 } else { next };
+                if ids.insert(name.clone(), id).is_some() {
+                    let msg = format!("duplicate block label {}", name);
+                    return Err((i, Error::Usage(msg)));
+                }
+                if id == next {
+                    next += 1;
+                }
+            }
+        }
+    }
+    for (i, tokens) in files.iter_mut().enumerate() {
+        for (pos, tok) in tokens.iter_mut() {
+            if let Tok::Label(name) = tok {
+                match ids.get(name) {
+                    Some(id) => *tok = Tok::Int(i64::from(*id)),
+                    None => {
+                        let msg =
+                            format!("{}: undefined block label {}", pos, name);
+                        return Err((i, Error::Usage(msg)));
+                    }
                 }
-            },
-        )).or(char('r')
-            .with(many1(digit()))
-            .map(|n: String| Tok::Reg(n.parse::<usize>().unwrap())))
-        .or(between(char('"'), char('"'), many1(alpha_num())).map(|x: String| Tok::Id(x)));
-
-    let ws = spaces();
-
-    let mut toks = spaces().with(sep_end_by(tok, ws)).skip(eof()).map(
-        |mut tokens: Vec<Tok>| {
-            tokens.push(Tok::Eof);
-            tokens
+            }
+        }
+    }
+    Ok(())
+}
+
+// Tokenizes `s`, pairing each token with the position where it starts, after
+// reading the directives at its top.
+fn lex(s: &str) -> Result<(Settings, Vec<(Pos, Tok)>), LexError<'_>> {
+    // A directive such as `#memory 2048`, which is a line of its own.
+    let blank = || skip_many(one_of(" \t\r".chars()));
+    let directive = char('#')
+        .with(many1(letter()))
+        .skip(skip_many1(one_of(" \t".chars())))
+        .and(many1(digit()))
+        .skip(blank())
+        .skip(choice((char('\n').map(|_x| ()), eof())))
+        .and_then(|(name, digits): (String, String)| {
+            let n = digits.parse::<usize>().map_err(|_| {
+                let msg = format!("#{} {} is too large", name, digits);
+                easy::Error::Message(msg.into())
+            })?;
+            match &name[..] {
+                "memory" if n < MIN_HEAP_SIZE => {
+                    let msg = format!("#memory {} is too small", n);
+                    Err(easy::Error::Message(msg.into()))
+                }
+                "memory" => Ok(Settings {
+                    memory: Some(n),
+                    ..Default::default()
+                }),
+                "registers" => Ok(Settings {
+                    registers: Some(n),
+                    ..Default::default()
+                }),
+                _ => {
+                    let msg = format!("unknown directive #{}", name);
+                    Err(easy::Error::Message(msg.into()))
+                }
+            }
+        });
+    let directives = many(directive.skip(ws())).and_then(
+        |directives: Vec<Settings>| {
+            let mut settings = Settings::default();
+            for directive in directives {
+                if let Err(msg) = settings.merge(directive) {
+                    return Err(easy::Error::Message(msg.into()));
+                }
+            }
+            Ok(settings)
         },
     );
-    toks.easy_parse(s).map(|tuple| tuple.0)
+
+    // combine's choice accepts at most 25 alternatives, so tokens are grouped
+    // by kind.
+    let punct = choice((
+        string("{").map(|_x| Tok::LBrace),
+        string("}").map(|_x| Tok::RBrace),
+        string("(").map(|_x| Tok::LParen),
+        string(")").map(|_x| Tok::RParen),
+        string("[").map(|_x| Tok::LBracket),
+        string("]").map(|_x| Tok::RBracket),
+        string(",").map(|_x| Tok::Comma),
+        string(";").map(|_x| Tok::Semi),
+    ));
+
+    // A keyword, a register, or a block label.
+    let word = (letter(), many(alpha_num().or(char('_'))))
+        .and_then(|(first, rest): (char, String)| {
+            let word = format!("{}{}", first, rest);
+            if let Some(tok) = keyword(&word) {
+                return Ok(tok);
+            }
+            let digits = &word[first.len_utf8()..];
+            if first != 'r'
+                || digits.is_empty()
+                || !digits.bytes().all(|b| b.is_ascii_digit())
+            {
+                return Ok(Tok::Label(word));
+            }
+            digits.parse::<usize>().map(Tok::Reg).map_err(|_| {
+                let msg = format!("register {} is too large", word);
+                easy::Error::Message(msg.into())
+            })
+        });
+
+    let op = choice((
+        attempt(string("==")).map(|_x| Tok::Op2(Op2::Eq)),
+        string("=").map(|_x| Tok::Equal),
+        string("+").map(|_x| Tok::Op2(Op2::Add)),
+        string("-").map(|_x| Tok::Op2(Op2::Sub)),
+        string("*").map(|_x| Tok::Op2(Op2::Mul)),
+        string("/").map(|_x| Tok::Op2(Op2::Div)),
+        string("%").map(|_x| Tok::Op2(Op2::Mod)),
+        attempt(string("<<")).map(|_x| Tok::Op2(Op2::Shl)),
+        attempt(string("<=")).map(|_x| Tok::Op2(Op2::LE)),
+        string("<").map(|_x| Tok::Op2(Op2::LT)),
+        attempt(string(">>")).map(|_x| Tok::Op2(Op2::Shr)),
+        attempt(string(">=")).map(|_x| Tok::Op2(Op2::GE)),
+        string(">").map(|_x| Tok::Op2(Op2::GT)),
+        string("!=").map(|_x| Tok::Op2(Op2::NE)),
+        string("&").map(|_x| Tok::Op2(Op2::And)),
+        string("|").map(|_x| Tok::Op2(Op2::Or)),
+        string("^").map(|_x| Tok::Op2(Op2::Xor)),
+    ));
+
+    // Digits in the given radix, which may be separated by underscores.
+    let digits = |radix: u32| {
+        many1(satisfy(move |c: char| c == '_' || c.is_digit(radix)))
+            .map(move |digits: String| (radix, digits))
+    };
+
+    // Signs are separate tokens; see `val`.
+    let int = choice((
+        attempt(char('0').skip(one_of("xX".chars()))).with(digits(16)),
+        attempt(char('0').skip(one_of("bB".chars()))).with(digits(2)),
+        digits(10),
+    )).and_then(|(radix, digits): (u32, String)| {
+        if digits.starts_with('_')
+            || digits.ends_with('_')
+            || digits.contains("__")
+        {
+            let msg = format!("misplaced _ in integer literal {}", digits);
+            return Err(easy::Error::Message(msg.into()));
+        }
+        let digits = digits.replace('_', "");
+        match i64::from_str_radix(&digits, radix) {
+            Ok(n) if n <= 1 << 31 => Ok(Tok::Int(n)),
+            _ => {
+                let msg = format!("integer literal {} is too large", digits);
+                Err(easy::Error::Message(msg.into()))
+            }
+        }
+    });
+
+    // A quoted name, in which `\"` and `\\` stand for `"` and `\`.
+    let escaped = char('\\').with(one_of("\"\\".chars()));
+    let unescaped = satisfy(|c| c != '"' && c != '\\' && c != '\n');
+    let name = between(
+        char('"'),
+        char('"'),
+        many(choice((escaped, unescaped))),
+    ).map(Tok::Id);
+
+    let literal = choice((int, name));
+
+    let tok = choice((punct, word, op, literal));
+
+    let pos = || {
+        position().map(|p: SourcePosition| Pos {
+            line: p.line,
+            column: p.column,
+        })
+    };
+
+    let mut toks = ws()
+        .with(directives)
+        .and(sep_end_by((pos(), tok), ws()))
+        .and(pos())
+        .skip(eof())
+        .map(|((settings, mut tokens), end): ((Settings, Vec<_>), Pos)| {
+            tokens.push((end, Tok::Eof));
+            (settings, tokens)
+        });
+    toks.easy_parse(State::new(s)).map(|tuple| tuple.0)
 }
 
 fn reg<I>() -> impl Parser<Input = I, Output = usize>
@@ -114,17 +595,75 @@ where
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
     satisfy_map(|t| match t {
-        Tok::Int32(n) => Option::Some(n),
+        Tok::Int(n) if n <= i64::from(i32::MAX) => Option::Some(n as i32),
+        _ => Option::None,
+    })
+}
+
+// An integer literal after a `-`.
+fn negated_i32<I>() -> impl Parser<Input = I, Output = i32>
+where
+    I: Stream<Item = Tok>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    satisfy_map(|t| match t {
+        Tok::Int(n) => Option::Some((-n) as i32),
         _ => Option::None,
     })
 }
 
+// An integer literal, which may have a sign.
+fn int<I>() -> impl Parser<Input = I, Output = i32>
+where
+    I: Stream<Item = Tok>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    choice((
+        i32(),
+        token(Tok::Op2(Op2::Sub)).with(negated_i32()),
+        token(Tok::Op2(Op2::Add)).with(i32()),
+    ))
+}
+
 fn val<I>() -> impl Parser<Input = I, Output = Val>
 where
     I: Stream<Item = Tok>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
-    reg().map(|r| Val::Reg(r)).or(i32().map(|n| Val::Imm(n)))
+    choice((reg().map(Val::Reg), int().map(Val::Imm)))
+}
+
+fn id<I>() -> impl Parser<Input = I, Output = String>
+where
+    I: Stream<Item = Tok>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    satisfy_map(|t| match t {
+        Tok::Id(s) => Option::Some(s),
+        _ => Option::None,
+    })
+}
+
+fn op_call<I>() -> impl Parser<Input = I, Output = Op2>
+where
+    I: Stream<Item = Tok>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    satisfy_map(|t| match t {
+        Tok::OpCall(op) => Option::Some(op),
+        _ => Option::None,
+    })
+}
+
+fn op1<I>() -> impl Parser<Input = I, Output = Op1>
+where
+    I: Stream<Item = Tok>,
+    I::Error: ParseError<I::Item, I::Range, I::Position>,
+{
+    satisfy_map(|t| match t {
+        Tok::Op1(op) => Option::Some(op),
+        _ => Option::None,
+    })
 }
 
 fn op2<I>() -> impl Parser<Input = I, Output = Op2>
@@ -143,30 +682,45 @@ where
     I: Stream<Item = Tok>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
-    let id = satisfy_map(|p| match p {
-        Tok::Id(s) => Option::Some(Printable::Id(s)),
-        _ => Option::None,
-    });
+    let id = id().map(Printable::Id);
 
-    let v = val().map(|v| Printable::Val(v));
+    let v = val().map(Printable::Val);
     
     let array = token(Tok::Array).skip(token(Tok::LParen)).with(val()).skip(token(Tok::Comma))
         .and(val()).skip(token(Tok::RParen))
         .map(|(v1, v2)| Printable::Array(v1, v2));
 
-    id.or(v).or(array)
+    let seq = token(Tok::Seq).skip(token(Tok::LParen)).with(val())
+        .skip(token(Tok::Comma)).and(val()).skip(token(Tok::RParen))
+        .map(|(v1, v2)| Printable::Seq(v1, v2));
+
+    let hex = token(Tok::Hex)
+        .with(between(token(Tok::LParen), token(Tok::RParen), val()))
+        .map(Printable::Hex);
+
+    choice((id, v, array, seq, hex))
 }
 
 enum AfterReg {
     Load(Val), // *v
     Copy(Val),
     Op2(Op2, Val, Val),
-    Malloc(Val)
+    Op1(Op1, Val),
+    Malloc(Val),
+    Calloc(Val),
+    Realloc(Reg, Val), // realloc(p, n)
+    LoadIdx(Reg, Val), // a[i]
+    FetchAdd(Reg, Val), // fetchadd(p, v)
+    Read,
+    StoreIdx(Val, Val), // [i] = v
 }
 
-fn instr_<I>() -> impl Parser<Input = I, Output = Instr>
+// Token indices at which the instructions of the current block start.
+type Starts = Rc<RefCell<Vec<usize>>>;
+
+fn instr_<I>(starts: Starts) -> impl Parser<Input = I, Output = Instr>
 where
-    I: Stream<Item = Tok>,
+    I: Stream<Item = Tok, Position = usize>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
     let goto = token(Tok::Goto)
@@ -174,48 +728,126 @@ where
         .with(val())
         .skip(token(Tok::RParen))
         .skip(token(Tok::Semi))
-        .map(|v| Instr::Goto(v));
+        .map(Instr::Goto);
 
     let abort = token(Tok::Abort)
-        .skip(token(Tok::Semi))
-        .map(|_x| Instr::Abort());
+        .with(optional(between(
+            token(Tok::LParen),
+            token(Tok::RParen),
+            optional(id()),
+        ))).skip(token(Tok::Semi))
+        .map(|reason| Instr::Abort(reason.and_then(|r| r)));
 
+    // exit(); is exit(0);
     let exit = token(Tok::Exit)
         .with(token(Tok::LParen))
-        .with(val())
+        .with(optional(val()))
         .skip(token(Tok::RParen))
         .skip(token(Tok::Semi))
-        .map(|v| Instr::Exit(v));
+        .map(|v| Instr::Exit(v.unwrap_or(Val::Imm(0))));
 
-    let copy_or_op2 = reg()
-        .skip(token(Tok::Equal))
-        .and(
-            token(Tok::Op2(Op2::Mul))
-                .with(val())
-                .skip(token(Tok::Semi))
-                .map(|v| AfterReg::Load(v))
-                .or(val()
-                    .and(
-                        token(Tok::Semi).map(|_x| None).or(op2()
-                            .and(val())
-                            .skip(token(Tok::Semi))
-                            .map(|p| Some(p))),
-                    ).map(|(v1, v2opt)| match v2opt {
-                        None => AfterReg::Copy(v1),
-                        Some((op, v2)) => AfterReg::Op2(op, v1, v2),
-                    }))
-                .or(token(Tok::Malloc)
-                    .with(between(token(Tok::LParen), token(Tok::RParen),
-                        val()))
+    let ret = token(Tok::Ret)
+        .with(between(token(Tok::LParen), token(Tok::RParen), val()))
+        .skip(token(Tok::Semi))
+        .map(Instr::Ret);
+
+    // What follows `r =`.
+    let assign = choice((
+        token(Tok::Op2(Op2::Mul))
+            .with(val())
+            .skip(token(Tok::Semi))
+            .map(AfterReg::Load),
+        attempt(reg().skip(token(Tok::LBracket)))
+            .and(val())
+            .skip(token(Tok::RBracket))
+            .skip(token(Tok::Semi))
+            .map(|(a, i)| AfterReg::LoadIdx(a, i)),
+        // `-r` is `0 - r`, but `-5` is an immediate.
+        attempt(token(Tok::Op2(Op2::Sub)).with(reg()))
+            .skip(token(Tok::Semi))
+            .map(|r| AfterReg::Op2(Op2::Sub, Val::Imm(0), Val::Reg(r))),
+        val()
+            .and(
+                token(Tok::Semi).map(|_x| None).or(op2()
+                    .and(val())
                     .skip(token(Tok::Semi))
-                    .map(|v| AfterReg::Malloc(v)))
-        ).and(instr())
+                    .map(Some)),
+            ).map(|(v1, v2opt)| match v2opt {
+                None => AfterReg::Copy(v1),
+                Some((op, v2)) => AfterReg::Op2(op, v1, v2),
+            }),
+        op_call()
+            .skip(token(Tok::LParen))
+            .and(val())
+            .skip(token(Tok::Comma))
+            .and(val())
+            .skip(token(Tok::RParen))
+            .skip(token(Tok::Semi))
+            .map(|((op, v1), v2)| AfterReg::Op2(op, v1, v2)),
+        op1()
+            .and(between(token(Tok::LParen), token(Tok::RParen), val()))
+            .skip(token(Tok::Semi))
+            .map(|(op, v)| AfterReg::Op1(op, v)),
+        token(Tok::Malloc)
+            .with(between(token(Tok::LParen), token(Tok::RParen),
+                val()))
+            .skip(token(Tok::Semi))
+            .map(AfterReg::Malloc),
+        token(Tok::Calloc)
+            .with(between(token(Tok::LParen), token(Tok::RParen), val()))
+            .skip(token(Tok::Semi))
+            .map(AfterReg::Calloc),
+        token(Tok::Realloc)
+            .with(token(Tok::LParen))
+            .with(reg())
+            .skip(token(Tok::Comma))
+            .and(val())
+            .skip(token(Tok::RParen))
+            .skip(token(Tok::Semi))
+            .map(|(p, v)| AfterReg::Realloc(p, v)),
+        token(Tok::FetchAdd)
+            .with(token(Tok::LParen))
+            .with(reg())
+            .skip(token(Tok::Comma))
+            .and(val())
+            .skip(token(Tok::RParen))
+            .skip(token(Tok::Semi))
+            .map(|(p, v)| AfterReg::FetchAdd(p, v)),
+        token(Tok::Read)
+            .skip(token(Tok::LParen))
+            .skip(token(Tok::RParen))
+            .skip(token(Tok::Semi))
+            .map(|_x| AfterReg::Read),
+    ));
+
+    // What follows `r` in `r[i] = v;`.
+    let index_store = token(Tok::LBracket)
+        .with(val())
+        .skip(token(Tok::RBracket))
+        .skip(token(Tok::Equal))
+        .and(val())
+        .skip(token(Tok::Semi))
+        .map(|(i, v)| AfterReg::StoreIdx(i, v));
+
+    let copy_or_op2 = reg()
+        .and(token(Tok::Equal).with(assign).or(index_store))
+        .and(instr(starts.clone()))
         .map(|((r, k), rest)| match k {
             AfterReg::Load(v) => Instr::Load(r, v, Box::new(rest)),
             AfterReg::Copy(v) => Instr::Copy(r, v, Box::new(rest)),
             AfterReg::Op2(op, v1, v2) =>
                 Instr::Op2(r, op, v1, v2, Box::new(rest)),
-            AfterReg::Malloc(v) => Instr::Malloc(r, v, Box::new(rest))
+            AfterReg::Op1(op, v) => Instr::Op1(r, op, v, Box::new(rest)),
+            AfterReg::Malloc(v) => Instr::Malloc(r, v, Box::new(rest)),
+            AfterReg::Calloc(v) => Instr::Calloc(r, v, Box::new(rest)),
+            AfterReg::Realloc(p, v) =>
+                Instr::Realloc(r, p, v, Box::new(rest)),
+            AfterReg::LoadIdx(a, i) => Instr::LoadIdx(r, a, i, Box::new(rest)),
+            AfterReg::StoreIdx(i, v) =>
+                Instr::StoreIdx(r, i, v, Box::new(rest)),
+            AfterReg::FetchAdd(p, v) =>
+                Instr::FetchAdd(r, p, v, Box::new(rest)),
+            AfterReg::Read => Instr::Read(r, Box::new(rest)),
         });
 
     let load = reg()
@@ -223,75 +855,310 @@ where
         .skip(token(Tok::Op2(Op2::Mul)))
         .and(val())
         .skip(token(Tok::Semi))
-        .and(instr())
+        .and(instr(starts.clone()))
         .map(|((r, v), rest)| Instr::Load(r, v, Box::new(rest)));
 
     let store = token(Tok::Op2(Op2::Mul))
-        .with(reg())
+        .with(val())
         .skip(token(Tok::Equal))
         .and(val())
         .skip(token(Tok::Semi))
-        .and(instr())
-        .map(|((r, v), rest)| Instr::Store(r, v, Box::new(rest)));
+        .and(instr(starts.clone()))
+        .map(|((a, v), rest)| Instr::Store(a, v, Box::new(rest)));
 
+    // `lower_ifzs` has given every ifz an else.
     let ifz = token(Tok::Ifz)
         .with(val())
-        .and(between(token(Tok::LBrace), token(Tok::RBrace), instr()))
-        .skip(token(Tok::Else))
-        .and(between(token(Tok::LBrace), token(Tok::RBrace), instr()))
+        .and(between(
+            token(Tok::LBrace),
+            token(Tok::RBrace),
+            instr(starts.clone()),
+        )).skip(token(Tok::Else))
+        .and(between(
+            token(Tok::LBrace),
+            token(Tok::RBrace),
+            instr(starts.clone()),
+        ))
         .map(|((v, tru), fls)| Instr::IfZ(v, Box::new(tru), Box::new(fls)));
 
     let free = token(Tok::Free)
         .with(between(token(Tok::LParen), token(Tok::RParen), reg()))
         .skip(token(Tok::Semi))
-        .and(instr())
+        .and(instr(starts.clone()))
         .map(|(r, rest)| Instr::Free(r, Box::new(rest)));
 
     let print = token(Tok::Print)
         .with(between(token(Tok::LParen), token(Tok::RParen), printable()))
         .skip(token(Tok::Semi))
-        .and(instr())
+        .and(instr(starts.clone()))
         .map(|(r, rest)| Instr::Print(r, Box::new(rest)));
 
-    goto.or(abort)
-        .or(exit)
-        .or(copy_or_op2)
-        .or(load)
-        .or(store)
-        .or(ifz)
-        .or(free)
-        .or(print)
+    let tag = token(Tok::Tag)
+        .with(between(
+            token(Tok::LParen),
+            token(Tok::RParen),
+            id().skip(token(Tok::Comma)).and(val()),
+        )).skip(token(Tok::Semi))
+        .and(instr(starts.clone()))
+        .map(|((name, v), rest)| Instr::Tag(name, v, Box::new(rest)));
+
+    let capture = token(Tok::Capture)
+        .with(between(
+            token(Tok::LParen),
+            token(Tok::RParen),
+            (
+                id().skip(token(Tok::Comma)),
+                val().skip(token(Tok::Comma)),
+                val(),
+            ),
+        )).skip(token(Tok::Semi))
+        .and(instr(starts.clone()))
+        .map(|((name, base, len), rest)| {
+            Instr::Capture(name, base, len, Box::new(rest))
+        });
+
+    let memcpy = token(Tok::Memcpy)
+        .with(between(
+            token(Tok::LParen),
+            token(Tok::RParen),
+            (
+                val().skip(token(Tok::Comma)),
+                val().skip(token(Tok::Comma)),
+                val(),
+            ),
+        )).skip(token(Tok::Semi))
+        .and(instr(starts.clone()))
+        .map(|((dst, src, n), rest)| {
+            Instr::Memcpy(dst, src, n, Box::new(rest))
+        });
+
+    let memset = token(Tok::Memset)
+        .with(between(
+            token(Tok::LParen),
+            token(Tok::RParen),
+            (
+                val().skip(token(Tok::Comma)),
+                val().skip(token(Tok::Comma)),
+                val(),
+            ),
+        )).skip(token(Tok::Semi))
+        .and(instr(starts.clone()))
+        .map(|((ptr, v, n), rest)| {
+            Instr::Memset(ptr, v, n, Box::new(rest))
+        });
+
+    let swap = token(Tok::Swap)
+        .with(between(
+            token(Tok::LParen),
+            token(Tok::RParen),
+            (reg().skip(token(Tok::Comma)), reg()),
+        )).skip(token(Tok::Semi))
+        .and(instr(starts.clone()))
+        .map(|((a, b), rest)| Instr::Swap(a, b, Box::new(rest)));
+
+    let assert = token(Tok::Assert)
+        .with(between(token(Tok::LParen), token(Tok::RParen), val()))
+        .skip(token(Tok::Semi))
+        .and(instr(starts.clone()))
+        .map(|(v, rest)| Instr::Assert(v, Box::new(rest)));
+
+    let spawn = token(Tok::Spawn)
+        .with(between(token(Tok::LParen), token(Tok::RParen), val()))
+        .skip(token(Tok::Semi))
+        .and(instr(starts.clone()))
+        .map(|(v, rest)| Instr::Spawn(v, Box::new(rest)));
+
+    let yield_ = token(Tok::Yield)
+        .skip(token(Tok::Semi))
+        .with(instr(starts.clone()))
+        .map(|rest| Instr::Yield(Box::new(rest)));
+
+    let call = token(Tok::Call)
+        .with(between(token(Tok::LParen), token(Tok::RParen), val()))
+        .skip(token(Tok::Semi))
+        .and(instr(starts.clone()))
+        .map(|(v, rest)| Instr::Call(v, Box::new(rest)));
+
+    choice((
+        goto,
+        abort,
+        exit,
+        copy_or_op2,
+        load,
+        store,
+        ifz,
+        free,
+        print,
+        tag,
+        capture,
+        swap,
+        assert,
+        memcpy,
+        memset,
+        spawn,
+        yield_,
+        call,
+        ret,
+    ))
 }
 
 parser!{
-    fn instr[I]()(I) -> Instr
-    where [I: Stream<Item = Tok>]
+    fn instr[I](starts: Starts)(I) -> Instr
+    where [I: Stream<Item = Tok, Position = usize>]
     {
-        instr_()
+        let starts = starts.clone();
+        position().and(instr_(starts.clone())).map(move |(start, instr)| {
+            starts.borrow_mut().push(start);
+            instr
+        })
     }
 }
 
-fn block<I>() -> impl Parser<Input = I, Output = Block>
+// A block whose body may be empty, until `fall_through` fills it in.
+type MaybeEmpty = (i32, Option<Instr>);
+
+// Also returns the token indices at which the block's instructions start, in
+// source order. An empty block's only instruction starts at `block`.
+fn block<I>(
+    starts: Starts,
+) -> impl Parser<Input = I, Output = (MaybeEmpty, Vec<usize>)>
 where
-    I: Stream<Item = Tok>,
+    I: Stream<Item = Tok, Position = usize>,
     I::Error: ParseError<I::Item, I::Range, I::Position>,
 {
-    token(Tok::Block).with(i32()).and(between(
-        token(Tok::LBrace),
-        token(Tok::RBrace),
-        instr(),
-    ))
+    position()
+        .skip(token(Tok::Block))
+        .and(int())
+        .and(between(
+            token(Tok::LBrace),
+            token(Tok::RBrace),
+            optional(instr(starts.clone())),
+        )).map(move |((start, id), body)| {
+            let mut block_starts = starts.replace(Vec::new());
+            block_starts.sort();
+            if body.is_none() {
+                block_starts.push(start);
+            }
+            ((id, body), block_starts)
+        })
+}
+
+// Makes each empty block in `files` jump to the block with the next larger
+// ID, which may be in any of the files.
+fn fall_through(
+    files: Vec<(Vec<MaybeEmpty>, SourceMap)>,
+) -> Result<Vec<Parsed>, (usize, Error)> {
+    let mut ids: Vec<i32> = files
+        .iter()
+        .flat_map(|(blocks, _)| blocks.iter().map(|(id, _)| *id))
+        .collect();
+    ids.sort();
+    files
+        .into_iter()
+        .enumerate()
+        .map(|(i, (blocks, positions))| {
+            let blocks = blocks
+                .into_iter()
+                .map(|(id, body)| match body {
+                    Some(instr) => Ok((id, instr)),
+                    None => match ids.iter().find(|other| **other > id) {
+                        Some(next) => Ok((id, Instr::Goto(Val::Imm(*next)))),
+                        None => {
+                            let msg = format!(
+                                "block {} is empty, and no block follows it",
+                                id
+                            );
+                            Err((i, Error::Usage(msg)))
+                        }
+                    },
+                }).collect::<Result<Vec<Block>, _>>()?;
+            Ok((blocks, positions))
+        }).collect()
 }
 
 pub fn parse(input: &str) -> Result<Vec<Block>, Error> {
-    match lex(input) {
-        Result::Err(e) => Result::Err(Error::Parse(format!("{:?}", e))),
-        Result::Ok(tokens) => {
-            let mut ast = many1(block()).skip(token(Tok::Eof));
-            match ast.easy_parse(&tokens[..]) {
-                Result::Err(e) => Result::Err(Error::Parse(format!("{:?}", e))),
-                Result::Ok(tuple) => Result::Ok(tuple.0),
+    parse_with_positions(input).map(|(blocks, _)| blocks)
+}
+
+// Like `parse`, but also returns the source position of every instruction.
+pub fn parse_with_positions(input: &str) -> Result<Parsed, Error> {
+    parse_with_settings(input).map(|(parsed, _)| parsed)
+}
+
+// Like `parse_with_positions`, but also returns the program's directives.
+pub fn parse_with_settings(input: &str) -> Result<(Parsed, Settings), Error> {
+    parse_sources(&[input])
+        .map(|(mut parsed, settings)| (parsed.remove(0), settings))
+        .map_err(|(_, err)| err)
+}
+
+type Parsed = (Vec<Block>, SourceMap);
+
+// Parses a program that is split across several sources, returning the
+// blocks and positions of each one, and the directives of them all. A block
+// label in one source may name a block in another. Errors come with the index
+// of the source they are in.
+pub fn parse_sources(
+    inputs: &[&str],
+) -> Result<(Vec<Parsed>, Settings), (usize, Error)> {
+    let mut files = Vec::new();
+    let mut settings = Settings::default();
+    for (i, input) in inputs.iter().enumerate() {
+        match lex(input) {
+            Result::Err(e) => {
+                let e = e.map_position(|p| Pos {
+                    line: p.line,
+                    column: p.column,
+                });
+                return Result::Err((i, Error::Parse(e.to_string())));
+            }
+            Result::Ok((file_settings, lexed)) => {
+                settings
+                    .merge(file_settings)
+                    .map_err(|msg| (i, Error::Parse(msg)))?;
+                files.push(lexed)
             }
         }
     }
+    lower_whiles(&mut files)?;
+    lower_ifzs(&mut files)?;
+    resolve_labels(&mut files)?;
+    let parsed = files
+        .into_iter()
+        .enumerate()
+        .map(|(i, lexed)| parse_tokens(lexed).map_err(|err| (i, err)))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((fall_through(parsed)?, settings))
+}
+
+fn parse_tokens(
+    lexed: Vec<(Pos, Tok)>,
+) -> Result<(Vec<MaybeEmpty>, SourceMap), Error> {
+    let (positions, tokens): (Vec<Pos>, Vec<Tok>) = lexed.into_iter().unzip();
+    let starts = Rc::new(RefCell::new(Vec::new()));
+    let mut ast = many1(block(starts)).skip(token(Tok::Eof));
+    match ast.easy_parse(State::new(&tokens[..])) {
+        Result::Err(e) => {
+            // Errors are at token indices, and the last token is Eof.
+            let e = e
+                .map_position(|i| positions[i.min(positions.len() - 1)])
+                .map_range(|toks| format!("{:?}", toks));
+            Result::Err(Error::Parse(e.to_string()))
+        }
+        Result::Ok(tuple) => {
+            let parsed: Vec<(MaybeEmpty, Vec<usize>)> = tuple.0;
+            let mut source_map = SourceMap::new();
+            let blocks = parsed
+                .into_iter()
+                .map(|(block, block_starts)| {
+                    let block_positions = block_starts
+                        .into_iter()
+                        .map(|i| positions[i])
+                        .collect();
+                    source_map.insert(block.0, block_positions);
+                    block
+                }).collect();
+            Result::Ok((blocks, source_map))
+        }
+    }
 }
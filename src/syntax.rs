@@ -1,6 +1,10 @@
+use std::collections::HashMap;
+use std::fmt;
+
 pub type Reg = usize;
 
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Val {
     Reg(usize),
     Imm(i32),
@@ -8,6 +12,7 @@ pub enum Val {
 
 // Clone is needed to tokenize.
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Op2 {
     Add,
     Sub,
@@ -15,29 +20,388 @@ pub enum Op2 {
     Div,
     Mod,
     LT,
-    Eq
+    Eq,
+    GT,
+    LE,
+    GE,
+    NE,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+    // Written as calls, e.g. min(a, b), since they have no symbol.
+    Min,
+    Max,
+    Pow,
+}
+
+impl Op2 {
+    // Whether the operator is written as a call instead of between its
+    // operands.
+    pub fn is_call(&self) -> bool {
+        matches!(self, Op2::Min | Op2::Max | Op2::Pow)
+    }
+}
+
+// Operators with one operand, which are written as calls, e.g. abs(v).
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Op1 {
+    Abs,
+    // -1, 0, or 1, as the operand is negative, zero, or positive.
+    Sign,
 }
 
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Printable {
     Id(String),
     Val(Val),
+    // v in hexadecimal.
+    Hex(Val),
+    // Registers v1 through v1 + v2 - 1.
+    Seq(Val, Val),
+    // Heap cells v1 through v1 + v2 - 1.
     Array(Val, Val),
 }
 
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Instr {
     Goto(Val),
     Exit(Val),
-    Abort(),
+    // Stops the program, with an optional reason.
+    Abort(Option<String>),
     Op2(Reg, Op2, Val, Val, Box<Instr>),
+    Op1(Reg, Op1, Val, Box<Instr>),
     Copy(Reg, Val, Box<Instr>),
+    // Exchanges the values of two registers.
+    Swap(Reg, Reg, Box<Instr>),
     Load(Reg, Val, Box<Instr>),
-    Store(Reg, Val, Box<Instr>),
+    Store(Val, Val, Box<Instr>),
+    // r = a[i]
+    LoadIdx(Reg, Reg, Val, Box<Instr>),
+    // a[i] = v
+    StoreIdx(Reg, Val, Val, Box<Instr>),
     IfZ(Val, Box<Instr>, Box<Instr>),
     Malloc(Reg, Val, Box<Instr>),
+    // Like malloc, but fills the block with zeros.
+    Calloc(Reg, Val, Box<Instr>),
+    // r = realloc(p, n)
+    Realloc(Reg, Reg, Val, Box<Instr>),
     Print(Printable, Box<Instr>),
     Free(Reg, Box<Instr>),
+    Tag(String, Val, Box<Instr>),
+    Capture(String, Val, Val, Box<Instr>),
+    // Fails unless v is nonzero.
+    Assert(Val, Box<Instr>),
+    // memcpy(dst, src, n)
+    Memcpy(Val, Val, Val, Box<Instr>),
+    // memset(ptr, v, n)
+    Memset(Val, Val, Val, Box<Instr>),
+    Spawn(Val, Box<Instr>),
+    Yield(Box<Instr>),
+    // r = fetchadd(p, v)
+    FetchAdd(Reg, Reg, Val, Box<Instr>),
+    // r = read()
+    Read(Reg, Box<Instr>),
+    // Runs block v, then the rest once it returns.
+    Call(Val, Box<Instr>),
+    // Returns v to the caller in r0.
+    Ret(Val),
+}
+
+// The kinds of instruction, as named by `Instr::kind`.
+pub const KINDS: [&str; 26] = [
+    "goto", "exit", "abort", "op2", "op1", "copy", "swap", "load", "store",
+    "ifz", "malloc", "calloc", "realloc", "print", "free", "tag", "capture",
+    "assert", "memcpy", "memset", "spawn", "yield", "fetchadd", "read", "call",
+    "ret",
+];
+
+impl Instr {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Instr::Goto(_) => "goto",
+            Instr::Exit(_) => "exit",
+            Instr::Abort(_) => "abort",
+            Instr::Op2(..) => "op2",
+            Instr::Op1(..) => "op1",
+            Instr::Copy(..) => "copy",
+            Instr::Swap(..) => "swap",
+            Instr::Load(..) | Instr::LoadIdx(..) => "load",
+            Instr::Store(..) | Instr::StoreIdx(..) => "store",
+            Instr::IfZ(..) => "ifz",
+            Instr::Malloc(..) => "malloc",
+            Instr::Calloc(..) => "calloc",
+            Instr::Realloc(..) => "realloc",
+            Instr::Print(..) => "print",
+            Instr::Free(..) => "free",
+            Instr::Tag(..) => "tag",
+            Instr::Capture(..) => "capture",
+            Instr::Assert(..) => "assert",
+            Instr::Memcpy(..) => "memcpy",
+            Instr::Memset(..) => "memset",
+            Instr::Spawn(..) => "spawn",
+            Instr::Yield(..) => "yield",
+            Instr::FetchAdd(..) => "fetchadd",
+            Instr::Read(..) => "read",
+            Instr::Call(..) => "call",
+            Instr::Ret(_) => "ret",
+        }
+    }
+
+    // This instruction and everything after it, in source order.
+    pub fn preorder(&self) -> Vec<&Instr> {
+        let mut instrs = vec![self];
+        for child in self.children() {
+            instrs.extend(child.preorder());
+        }
+        instrs
+    }
+
+    // The registers that this instruction (but not the ones after it)
+    // mentions.
+    pub fn registers(&self) -> Vec<Reg> {
+        let (regs, vals): (Vec<Reg>, Vec<&Val>) = match self {
+            Instr::Abort(_) | Instr::Yield(_) => (vec![], vec![]),
+            Instr::Goto(v)
+            | Instr::Exit(v)
+            | Instr::Spawn(v, _)
+            | Instr::Call(v, _)
+            | Instr::IfZ(v, _, _)
+            | Instr::Tag(_, v, _)
+            | Instr::Assert(v, _) => (vec![], vec![v]),
+            Instr::Op2(r, _, v1, v2, _) => (vec![*r], vec![v1, v2]),
+            Instr::Store(a, v, _) => (vec![], vec![a, v]),
+            Instr::Copy(r, v, _)
+            | Instr::Op1(r, _, v, _)
+            | Instr::Load(r, v, _)
+            | Instr::Malloc(r, v, _)
+            | Instr::Calloc(r, v, _) => (vec![*r], vec![v]),
+            Instr::LoadIdx(r, a, i, _) => (vec![*r, *a], vec![i]),
+            Instr::StoreIdx(a, i, v, _) => (vec![*a], vec![i, v]),
+            Instr::FetchAdd(r, p, v, _) | Instr::Realloc(r, p, v, _) => {
+                (vec![*r, *p], vec![v])
+            }
+            Instr::Print(Printable::Id(_), _) => (vec![], vec![]),
+            Instr::Print(Printable::Val(v), _)
+            | Instr::Print(Printable::Hex(v), _) => (vec![], vec![v]),
+            Instr::Print(Printable::Seq(v1, v2), _)
+            | Instr::Print(Printable::Array(v1, v2), _)
+            | Instr::Capture(_, v1, v2, _) => (vec![], vec![v1, v2]),
+            Instr::Memcpy(v1, v2, v3, _) | Instr::Memset(v1, v2, v3, _) => {
+                (vec![], vec![v1, v2, v3])
+            }
+            Instr::Free(r, _) | Instr::Read(r, _) => (vec![*r], vec![]),
+            Instr::Swap(a, b, _) => (vec![*a, *b], vec![]),
+            Instr::Ret(v) => (vec![0], vec![v]),
+        };
+        let val_regs = vals.into_iter().filter_map(|v| match v {
+            Val::Reg(r) => Some(*r),
+            Val::Imm(_) => None,
+        });
+        regs.into_iter().chain(val_regs).collect()
+    }
+
+    // The instructions that may run immediately after this one, in source
+    // order.
+    pub fn children(&self) -> Vec<&Instr> {
+        match self {
+            Instr::Goto(_)
+            | Instr::Exit(_)
+            | Instr::Abort(_)
+            | Instr::Ret(_) => vec![],
+            Instr::IfZ(_, true_part, false_part) => vec![true_part, false_part],
+            Instr::Op2(_, _, _, _, rest)
+            | Instr::Op1(_, _, _, rest)
+            | Instr::Copy(_, _, rest)
+            | Instr::Swap(_, _, rest)
+            | Instr::Load(_, _, rest)
+            | Instr::Store(_, _, rest)
+            | Instr::LoadIdx(_, _, _, rest)
+            | Instr::StoreIdx(_, _, _, rest)
+            | Instr::Malloc(_, _, rest)
+            | Instr::Calloc(_, _, rest)
+            | Instr::Realloc(_, _, _, rest)
+            | Instr::Print(_, rest)
+            | Instr::Free(_, rest)
+            | Instr::Tag(_, _, rest)
+            | Instr::Capture(_, _, _, rest)
+            | Instr::Assert(_, rest)
+            | Instr::Memcpy(_, _, _, rest)
+            | Instr::Memset(_, _, _, rest)
+            | Instr::Spawn(_, rest)
+            | Instr::Yield(rest)
+            | Instr::FetchAdd(_, _, _, rest)
+            | Instr::Read(_, rest)
+            | Instr::Call(_, rest) => vec![rest],
+        }
+    }
 }
 
 pub type Block = (i32, Instr);
+
+// Shows `blocks` in concrete syntax, one instruction per line.
+pub fn format_blocks(blocks: &[Block]) -> String {
+    let mut out = String::new();
+    for (id, instr) in blocks {
+        out.push_str(&format!("block {} {{\n", id));
+        format_instrs(&mut out, instr, 1);
+        out.push_str("}\n");
+    }
+    out
+}
+
+// Appends `instr` and the instructions after it to `out`, indented by `depth`
+// levels.
+fn format_instrs(out: &mut String, instr: &Instr, depth: usize) {
+    let indent = "    ".repeat(depth);
+    out.push_str(&format!("{}{}", indent, instr));
+    match instr {
+        Instr::IfZ(_, true_part, false_part) => {
+            out.push_str(" {\n");
+            format_instrs(out, true_part, depth + 1);
+            out.push_str(&format!("{}}}\n{}else {{\n", indent, indent));
+            format_instrs(out, false_part, depth + 1);
+            out.push_str(&format!("{}}}\n", indent));
+        }
+        _ => {
+            out.push('\n');
+            for child in instr.children() {
+                format_instrs(out, child, depth);
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Pos {
+    pub line: i32,
+    pub column: i32,
+}
+
+impl fmt::Display for Val {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Val::Reg(r) => write!(f, "r{}", r),
+            Val::Imm(n) => write!(f, "{}", n),
+        }
+    }
+}
+
+impl fmt::Display for Op2 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Op2::Add => "+",
+            Op2::Sub => "-",
+            Op2::Mul => "*",
+            Op2::Div => "/",
+            Op2::Mod => "%",
+            Op2::LT => "<",
+            Op2::Eq => "==",
+            Op2::GT => ">",
+            Op2::LE => "<=",
+            Op2::GE => ">=",
+            Op2::NE => "!=",
+            Op2::And => "&",
+            Op2::Or => "|",
+            Op2::Xor => "^",
+            Op2::Shl => "<<",
+            Op2::Shr => ">>",
+            Op2::Min => "min",
+            Op2::Max => "max",
+            Op2::Pow => "pow",
+        })
+    }
+}
+
+impl fmt::Display for Op1 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Op1::Abs => "abs",
+            Op1::Sign => "sign",
+        })
+    }
+}
+
+// `s` in quotes, as the parser reads it back.
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+impl fmt::Display for Printable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Printable::Id(s) => write!(f, "{}", quote(s)),
+            Printable::Val(v) => write!(f, "{}", v),
+            Printable::Hex(v) => write!(f, "hex({})", v),
+            Printable::Seq(v1, v2) => write!(f, "seq({}, {})", v1, v2),
+            Printable::Array(v1, v2) => write!(f, "array({}, {})", v1, v2),
+        }
+    }
+}
+
+// Shows a single instruction in concrete syntax, without the instructions
+// after it. `ifz` shows only its condition.
+impl fmt::Display for Instr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Instr::Goto(v) => write!(f, "goto({});", v),
+            Instr::Exit(v) => write!(f, "exit({});", v),
+            Instr::Abort(None) => write!(f, "abort;"),
+            Instr::Abort(Some(msg)) => write!(f, "abort({});", quote(msg)),
+            Instr::Op2(r, op, v1, v2, _) if op.is_call() => {
+                write!(f, "r{} = {}({}, {});", r, op, v1, v2)
+            }
+            Instr::Op2(r, op, v1, v2, _) => {
+                write!(f, "r{} = {} {} {};", r, v1, op, v2)
+            }
+            Instr::Op1(r, op, v, _) => write!(f, "r{} = {}({});", r, op, v),
+            Instr::Copy(r, v, _) => write!(f, "r{} = {};", r, v),
+            Instr::Swap(a, b, _) => write!(f, "swap(r{}, r{});", a, b),
+            Instr::Load(r, v, _) => write!(f, "r{} = *{};", r, v),
+            Instr::Store(a, v, _) => write!(f, "*{} = {};", a, v),
+            Instr::LoadIdx(r, a, i, _) => write!(f, "r{} = r{}[{}];", r, a, i),
+            Instr::StoreIdx(a, i, v, _) => write!(f, "r{}[{}] = {};", a, i, v),
+            Instr::IfZ(v, _, _) => write!(f, "ifz {}", v),
+            Instr::Malloc(r, v, _) => write!(f, "r{} = malloc({});", r, v),
+            Instr::Calloc(r, v, _) => write!(f, "r{} = calloc({});", r, v),
+            Instr::Realloc(r, p, v, _) => {
+                write!(f, "r{} = realloc(r{}, {});", r, p, v)
+            }
+            Instr::Print(p, _) => write!(f, "print({});", p),
+            Instr::Free(r, _) => write!(f, "free(r{});", r),
+            Instr::Tag(name, v, _) => write!(f, "tag({}, {});", quote(name), v),
+            Instr::Capture(name, v1, v2, _) => {
+                write!(f, "capture({}, {}, {});", quote(name), v1, v2)
+            }
+            Instr::Assert(v, _) => write!(f, "assert({});", v),
+            Instr::Memcpy(v1, v2, v3, _) => {
+                write!(f, "memcpy({}, {}, {});", v1, v2, v3)
+            }
+            Instr::Memset(v1, v2, v3, _) => {
+                write!(f, "memset({}, {}, {});", v1, v2, v3)
+            }
+            Instr::Spawn(v, _) => write!(f, "spawn({});", v),
+            Instr::Yield(_) => write!(f, "yield;"),
+            Instr::FetchAdd(r, p, v, _) => {
+                write!(f, "r{} = fetchadd(r{}, {});", r, p, v)
+            }
+            Instr::Read(r, _) => write!(f, "r{} = read();", r),
+            Instr::Call(v, _) => write!(f, "call({});", v),
+            Instr::Ret(v) => write!(f, "ret({});", v),
+        }
+    }
+}
+
+impl fmt::Display for Pos {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+// For each block, the source position of every instruction in the order the
+// parser saw them, which is a pre-order walk of the block's instructions.
+pub type SourceMap = HashMap<i32, Vec<Pos>>;
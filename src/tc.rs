@@ -4,21 +4,634 @@ use std::collections::HashSet;
 use std::hash::Hash;
 use syntax;
 
-fn has_unique_elements<T>(iter: T) -> bool
+// The elements that occur more than once in `iter`, each once, in order.
+fn duplicates<T>(iter: T) -> Vec<T::Item>
 where
     T: IntoIterator,
-    T::Item: Eq + Hash,
+    T::Item: Ord + Hash + Clone,
 {
     let mut uniq = HashSet::new();
-    iter.into_iter().all(move |x| uniq.insert(x))
+    let mut dups: Vec<T::Item> = iter
+        .into_iter()
+        .filter(|x| !uniq.insert(x.clone()))
+        .collect();
+    dups.sort();
+    dups.dedup();
+    dups
 }
 
 pub fn tc(
     blocks: Vec<syntax::Block>,
 ) -> Result<HashMap<i32, syntax::Instr>, Error> {
-    if !has_unique_elements(blocks.iter().map(|tuple| tuple.0)) {
-        return Err(Error::Usage("duplicate block IDs".to_string()));
+    let dups = duplicates(blocks.iter().map(|tuple| tuple.0));
+    if !dups.is_empty() {
+        let ids: Vec<String> = dups.iter().map(|id| id.to_string()).collect();
+        let msg = format!("duplicate block IDs: {}", ids.join(", "));
+        return Err(Error::Usage(msg));
+    }
+    // Programs start at block 0.
+    if !blocks.iter().any(|(id, _)| *id == 0) {
+        return Err(Error::Usage("Expected block 0".to_string()));
+    }
+
+    // Indirect gotos, calls, and spawns are checked when they run.
+    let ids: HashSet<i32> = blocks.iter().map(|tuple| tuple.0).collect();
+    let mut missing: Vec<i32> = blocks
+        .iter()
+        .flat_map(|tuple| tuple.1.preorder())
+        .filter_map(|instr| match instr {
+            syntax::Instr::Goto(syntax::Val::Imm(n))
+            | syntax::Instr::Call(syntax::Val::Imm(n), _)
+            | syntax::Instr::Spawn(syntax::Val::Imm(n), _)
+                if !ids.contains(n) =>
+            {
+                Some(*n)
+            }
+            _ => None,
+        })
+        .collect();
+    if !missing.is_empty() {
+        missing.sort();
+        missing.dedup();
+        let ids: Vec<String> = missing.iter().map(|n| n.to_string()).collect();
+        let msg = format!("undefined blocks: {}", ids.join(", "));
+        return Err(Error::Usage(msg));
     }
 
     Ok(blocks.into_iter().collect())
 }
+
+// The block that `instr` unconditionally jumps to, if it is a straight-line
+// sequence that cannot fail, ending in `goto` with a constant target.
+fn unconditional_target(instr: &syntax::Instr) -> Option<i32> {
+    use syntax::Instr::*;
+    if can_fail(instr) {
+        return None;
+    }
+    match instr {
+        Goto(syntax::Val::Imm(n)) => Some(*n),
+        Goto(syntax::Val::Reg(_))
+        | Exit(_)
+        | Abort(_)
+        | Assert(..)
+        | IfZ(..)
+        // The callee may never return.
+        | Call(..)
+        | Ret(_) => None,
+        Op2(_, _, _, _, rest)
+        | Op1(_, _, _, rest)
+        | Copy(_, _, rest)
+        | Load(_, _, rest)
+        | Store(_, _, rest)
+        | LoadIdx(_, _, _, rest)
+        | StoreIdx(_, _, _, rest)
+        | Malloc(_, _, rest)
+        | Calloc(_, _, rest)
+        | Realloc(_, _, _, rest)
+        | Print(_, rest)
+        | Free(_, rest)
+        | Tag(_, _, rest)
+        | Capture(_, _, _, rest)
+        | Memcpy(_, _, _, rest)
+        | Memset(_, _, _, rest)
+        | Spawn(_, rest)
+        | Yield(rest)
+        | FetchAdd(_, _, _, rest)
+        | Read(_, rest)
+        | Swap(_, _, rest) => unconditional_target(rest),
+    }
+}
+
+// Whether `instr` may stop the program with a runtime error, such as a failed
+// assertion, a division by zero, a bad address, or a read past the end of the
+// input. Jumps are left to `unconditional_target`.
+fn can_fail(instr: &syntax::Instr) -> bool {
+    use syntax::Instr::*;
+    use syntax::Op2 as O;
+    match instr {
+        // The comparisons and bitwise operators are the only ones that cannot
+        // overflow or divide by zero.
+        Op2(_, op, _, _, _) => !matches!(
+            op,
+            O::LT
+                | O::Eq
+                | O::GT
+                | O::LE
+                | O::GE
+                | O::NE
+                | O::And
+                | O::Or
+                | O::Xor
+                | O::Min
+                | O::Max
+        ),
+        Op1(_, op, _, _) => *op == syntax::Op1::Abs,
+        Print(p, _) => matches!(
+            p,
+            syntax::Printable::Seq(..) | syntax::Printable::Array(..)
+        ),
+        Assert(..)
+        | Load(..)
+        | Store(..)
+        | LoadIdx(..)
+        | StoreIdx(..)
+        | Malloc(..)
+        | Calloc(..)
+        | Realloc(..)
+        | Free(..)
+        | Capture(..)
+        | Memcpy(..)
+        | Memset(..)
+        | Spawn(..)
+        | FetchAdd(..)
+        | Read(..) => true,
+        Goto(_) | Exit(_) | Abort(_) | IfZ(..) | Call(..) | Ret(_)
+        | Copy(..) | Swap(..) | Tag(..) | Yield(_) => false,
+    }
+}
+
+// Cycles of blocks that jump to each other with no branch, exit, abort, or
+// instruction that can fail along the way. Once control enters such a cycle,
+// the program cannot terminate at all. Each cycle is sorted, and cycles are
+// ordered by their smallest block.
+pub fn infinite_loops(blocks: &HashMap<i32, syntax::Instr>) -> Vec<Vec<i32>> {
+    let succ: HashMap<i32, i32> = blocks
+        .iter()
+        .filter_map(|(id, instr)| {
+            unconditional_target(instr).map(|n| (*id, n))
+        })
+        .collect();
+    let mut ids: Vec<i32> = succ.keys().cloned().collect();
+    ids.sort();
+    let mut seen = HashSet::new();
+    let mut cycles = Vec::new();
+    for id in ids {
+        if seen.contains(&id) {
+            continue;
+        }
+        // Walk at most |succ| steps; if we come back to `id`, it is on a cycle.
+        let mut cycle = vec![id];
+        let mut cur = succ.get(&id);
+        while let Some(&next) = cur {
+            if next == id || cycle.len() > succ.len() {
+                break;
+            }
+            cycle.push(next);
+            cur = succ.get(&next);
+        }
+        if cur == Some(&id) {
+            cycle.sort();
+            seen.extend(cycle.iter().cloned());
+            cycles.push(cycle);
+        }
+    }
+    cycles
+}
+
+// The blocks that no path from block 0 reaches, in order. Constant `goto`,
+// `call`, and `spawn` targets are followed. A reachable jump through a
+// register may go anywhere, so then no block is reported.
+pub fn unreachable_blocks(blocks: &HashMap<i32, syntax::Instr>) -> Vec<i32> {
+    use syntax::Instr::*;
+    use syntax::Val::*;
+    let mut reached = HashSet::new();
+    let mut work = vec![0];
+    while let Some(id) = work.pop() {
+        let instr = match blocks.get(&id) {
+            Some(instr) => instr,
+            None => continue,
+        };
+        if !reached.insert(id) {
+            continue;
+        }
+        for instr in instr.preorder() {
+            match instr {
+                Goto(Imm(n)) | Call(Imm(n), _) | Spawn(Imm(n), _) => {
+                    work.push(*n)
+                }
+                Goto(Reg(_)) | Call(Reg(_), _) | Spawn(Reg(_), _) => {
+                    return Vec::new()
+                }
+                _ => (),
+            }
+        }
+    }
+    let mut ids: Vec<i32> = blocks
+        .keys()
+        .filter(|id| !reached.contains(id))
+        .cloned()
+        .collect();
+    ids.sort();
+    ids
+}
+
+// Where control may go next when a path through a block ends.
+enum Jump {
+    To(i32),
+    Anywhere,
+}
+
+// Whether `v` may hold a pointer, given the registers `ptrs` that may.
+fn may_point(ptrs: &HashSet<syntax::Reg>, v: &syntax::Val) -> bool {
+    match v {
+        syntax::Val::Reg(r) => ptrs.contains(r),
+        syntax::Val::Imm(_) => false,
+    }
+}
+
+// Follows every path through `instr`, starting with the registers `ptrs`
+// that may hold pointers. Registers that stores write through without
+// possibly holding a pointer go to `bad`, and the registers that may hold
+// pointers at each jump go to `jumps`.
+fn pointer_flow(
+    instr: &syntax::Instr,
+    mut ptrs: HashSet<syntax::Reg>,
+    bad: &mut Vec<syntax::Reg>,
+    jumps: &mut Vec<(Jump, HashSet<syntax::Reg>)>,
+) {
+    use syntax::Instr::*;
+    let rest = match instr {
+        Goto(syntax::Val::Imm(n)) => {
+            jumps.push((Jump::To(*n), ptrs));
+            return;
+        }
+        Goto(syntax::Val::Reg(_)) => {
+            jumps.push((Jump::Anywhere, ptrs));
+            return;
+        }
+        // Flow after a call is followed from the call itself.
+        Exit(_) | Abort(_) | Ret(_) => return,
+        IfZ(_, true_part, false_part) => {
+            pointer_flow(true_part, ptrs.clone(), bad, jumps);
+            pointer_flow(false_part, ptrs, bad, jumps);
+            return;
+        }
+        // Pointer arithmetic keeps a pointer a pointer.
+        Op2(r, op, v1, v2, rest) => {
+            let arith = *op == syntax::Op2::Add || *op == syntax::Op2::Sub;
+            if arith && (may_point(&ptrs, v1) || may_point(&ptrs, v2)) {
+                ptrs.insert(*r);
+            } else {
+                ptrs.remove(r);
+            }
+            rest
+        }
+        Copy(r, v, rest) => {
+            if may_point(&ptrs, v) {
+                ptrs.insert(*r);
+            } else {
+                ptrs.remove(r);
+            }
+            rest
+        }
+        Op1(r, _, _, rest) | Read(r, rest) => {
+            ptrs.remove(r);
+            rest
+        }
+        Swap(a, b, rest) => {
+            let (a_ptr, b_ptr) = (ptrs.contains(a), ptrs.contains(b));
+            for (r, is_ptr) in &[(*a, b_ptr), (*b, a_ptr)] {
+                if *is_ptr {
+                    ptrs.insert(*r);
+                } else {
+                    ptrs.remove(r);
+                }
+            }
+            rest
+        }
+        // Anything read from the heap may be a pointer.
+        Malloc(r, _, rest)
+        | Calloc(r, _, rest)
+        | Realloc(r, _, _, rest)
+        | Load(r, _, rest)
+        | LoadIdx(r, _, _, rest)
+        | FetchAdd(r, _, _, rest) => {
+            ptrs.insert(*r);
+            rest
+        }
+        // A store through a constant address names no register to blame.
+        Store(syntax::Val::Imm(_), _, rest) => rest,
+        Store(syntax::Val::Reg(r), _, rest) | StoreIdx(r, _, _, rest) => {
+            if !ptrs.contains(r) {
+                bad.push(*r);
+            }
+            rest
+        }
+        // Spawned blocks start with every register zero.
+        Spawn(syntax::Val::Imm(n), rest) => {
+            jumps.push((Jump::To(*n), HashSet::new()));
+            rest
+        }
+        Spawn(syntax::Val::Reg(_), rest) => {
+            jumps.push((Jump::Anywhere, HashSet::new()));
+            rest
+        }
+        // This assumes that the callee leaves the caller's pointers alone.
+        // The value it returns in r0 may be a pointer.
+        Call(v, rest) => {
+            let jump = match v {
+                syntax::Val::Imm(n) => Jump::To(*n),
+                syntax::Val::Reg(_) => Jump::Anywhere,
+            };
+            jumps.push((jump, ptrs.clone()));
+            ptrs.insert(0);
+            rest
+        }
+        Print(_, rest)
+        | Free(_, rest)
+        | Tag(_, _, rest)
+        | Capture(_, _, _, rest)
+        | Assert(_, rest)
+        | Memcpy(_, _, _, rest)
+        | Memset(_, _, _, rest)
+        | Yield(rest) => rest,
+    };
+    pointer_flow(rest, ptrs, bad, jumps)
+}
+
+// Stores through registers that cannot hold a pointer on any path from block
+// 0, as (block, register) pairs ordered by block. This is a best-effort
+// check: a register holds a pointer if it came from `malloc`, the heap, or
+// arithmetic on a pointer. `entry` is the registers that hold pointers when
+// the program starts.
+pub fn bad_stores(
+    blocks: &HashMap<i32, syntax::Instr>,
+    entry: HashSet<syntax::Reg>,
+) -> Vec<(i32, syntax::Reg)> {
+    let mut ins: HashMap<i32, HashSet<syntax::Reg>> = HashMap::new();
+    ins.insert(0, entry);
+    let mut work = vec![0];
+    while let Some(id) = work.pop() {
+        let instr = match blocks.get(&id) {
+            Some(instr) => instr,
+            None => continue,
+        };
+        let mut jumps = Vec::new();
+        pointer_flow(instr, ins[&id].clone(), &mut Vec::new(), &mut jumps);
+        for (jump, ptrs) in jumps {
+            let targets: Vec<i32> = match jump {
+                Jump::To(n) => vec![n],
+                Jump::Anywhere => blocks.keys().cloned().collect(),
+            };
+            for target in targets {
+                let before = ins.get(&target).map(|set| set.len());
+                let set = ins.entry(target).or_default();
+                set.extend(ptrs.iter().cloned());
+                if before != Some(set.len()) {
+                    work.push(target);
+                }
+            }
+        }
+    }
+    let mut ids: Vec<&i32> =
+        ins.keys().filter(|id| blocks.contains_key(id)).collect();
+    ids.sort();
+    let mut stores = Vec::new();
+    for id in ids {
+        let mut bad = Vec::new();
+        pointer_flow(&blocks[id], ins[id].clone(), &mut bad, &mut Vec::new());
+        stores.extend(bad.into_iter().map(|r| (*id, r)));
+    }
+    stores
+}
+
+// Rejects programs that mention a register that a machine with
+// `num_registers` registers does not have.
+pub fn check_registers(
+    blocks: &HashMap<i32, syntax::Instr>,
+    num_registers: usize,
+) -> Result<(), Error> {
+    let mut ids: Vec<&i32> = blocks.keys().collect();
+    ids.sort();
+    for id in ids {
+        for instr in blocks[id].preorder() {
+            if let Some(r) =
+                instr.registers().into_iter().find(|r| *r >= num_registers)
+            {
+                return Err(Error::Usage(format!(
+                    "block {} uses r{}, but there are only {} registers",
+                    id, r, num_registers
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+// The highest-numbered register that the program mentions, if any.
+pub fn highest_register(blocks: &HashMap<i32, syntax::Instr>) -> Option<usize> {
+    blocks
+        .values()
+        .flat_map(|block| block.preorder())
+        .flat_map(|instr| instr.registers())
+        .max()
+}
+
+// Rejects programs that use any of the `denied` kinds of instruction (see
+// `syntax::KINDS`).
+pub fn deny(
+    blocks: &HashMap<i32, syntax::Instr>,
+    denied: &[String],
+) -> Result<(), Error> {
+    for kind in denied {
+        if !syntax::KINDS.contains(&kind.as_str()) {
+            return Err(Error::Usage(format!(
+                "unknown instruction kind {}",
+                kind
+            )));
+        }
+    }
+    let mut ids: Vec<&i32> = blocks.keys().collect();
+    ids.sort();
+    for id in ids {
+        for instr in blocks[id].preorder() {
+            if denied.iter().any(|kind| kind == instr.kind()) {
+                return Err(Error::Usage(format!(
+                    "block {} uses denied instruction {}",
+                    id,
+                    instr.kind()
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+// The registers that `instr` (but not the ones after it) reads. The registers
+// that `print(seq(..))` reads are chosen at runtime, so they are left out.
+fn reads(instr: &syntax::Instr) -> Vec<syntax::Reg> {
+    use syntax::Instr::*;
+    use syntax::Printable;
+    let (regs, vals): (Vec<syntax::Reg>, Vec<&syntax::Val>) = match instr {
+        Abort(_) | Yield(_) | Read(..) | Print(Printable::Id(_), _) => {
+            (vec![], vec![])
+        }
+        Goto(v)
+        | Exit(v)
+        | Ret(v)
+        | Call(v, _)
+        | Spawn(v, _)
+        | IfZ(v, _, _)
+        | Tag(_, v, _)
+        | Assert(v, _)
+        | Op1(_, _, v, _)
+        | Copy(_, v, _)
+        | Load(_, v, _)
+        | Malloc(_, v, _)
+        | Calloc(_, v, _)
+        | Print(Printable::Val(v), _)
+        | Print(Printable::Hex(v), _) => (vec![], vec![v]),
+        Op2(_, _, v1, v2, _)
+        | Capture(_, v1, v2, _)
+        | Print(Printable::Seq(v1, v2), _)
+        | Print(Printable::Array(v1, v2), _) => (vec![], vec![v1, v2]),
+        Memcpy(v1, v2, v3, _) | Memset(v1, v2, v3, _) => {
+            (vec![], vec![v1, v2, v3])
+        }
+        Store(a, v, _) => (vec![], vec![a, v]),
+        LoadIdx(_, a, i, _) => (vec![*a], vec![i]),
+        StoreIdx(a, i, v, _) => (vec![*a], vec![i, v]),
+        FetchAdd(_, p, v, _) | Realloc(_, p, v, _) => (vec![*p], vec![v]),
+        Free(r, _) => (vec![*r], vec![]),
+        Swap(a, b, _) => (vec![*a, *b], vec![]),
+    };
+    let val_regs = vals.into_iter().filter_map(|v| match v {
+        syntax::Val::Reg(r) => Some(*r),
+        syntax::Val::Imm(_) => None,
+    });
+    regs.into_iter().chain(val_regs).collect()
+}
+
+// Follows every path through `instr`, starting with the registers `written`
+// that every path to it has written. Registers read before they are written
+// go to `bad`, and the registers written at each jump go to `jumps`.
+fn init_flow(
+    instr: &syntax::Instr,
+    mut written: HashSet<syntax::Reg>,
+    bad: &mut Vec<syntax::Reg>,
+    jumps: &mut Vec<(Jump, HashSet<syntax::Reg>)>,
+) {
+    use syntax::Instr::*;
+    bad.extend(reads(instr).into_iter().filter(|r| !written.contains(r)));
+    let rest = match instr {
+        Goto(syntax::Val::Imm(n)) => {
+            jumps.push((Jump::To(*n), written));
+            return;
+        }
+        Goto(syntax::Val::Reg(_)) => {
+            jumps.push((Jump::Anywhere, written));
+            return;
+        }
+        Exit(_) | Abort(_) | Ret(_) => return,
+        IfZ(_, true_part, false_part) => {
+            init_flow(true_part, written.clone(), bad, jumps);
+            init_flow(false_part, written, bad, jumps);
+            return;
+        }
+        Op2(r, _, _, _, rest)
+        | Op1(r, _, _, rest)
+        | Copy(r, _, rest)
+        | Load(r, _, rest)
+        | LoadIdx(r, _, _, rest)
+        | Malloc(r, _, rest)
+        | Calloc(r, _, rest)
+        | Realloc(r, _, _, rest)
+        | FetchAdd(r, _, _, rest)
+        | Read(r, rest) => {
+            written.insert(*r);
+            rest
+        }
+        Swap(a, b, rest) => {
+            written.insert(*a);
+            written.insert(*b);
+            rest
+        }
+        // Spawned blocks start with no registers written.
+        Spawn(syntax::Val::Imm(n), rest) => {
+            jumps.push((Jump::To(*n), HashSet::new()));
+            rest
+        }
+        Spawn(syntax::Val::Reg(_), rest) => {
+            jumps.push((Jump::Anywhere, HashSet::new()));
+            rest
+        }
+        // The callee returns a value in r0.
+        Call(v, rest) => {
+            let jump = match v {
+                syntax::Val::Imm(n) => Jump::To(*n),
+                syntax::Val::Reg(_) => Jump::Anywhere,
+            };
+            jumps.push((jump, written.clone()));
+            written.insert(0);
+            rest
+        }
+        Store(_, _, rest)
+        | StoreIdx(_, _, _, rest)
+        | Print(_, rest)
+        | Free(_, rest)
+        | Tag(_, _, rest)
+        | Capture(_, _, _, rest)
+        | Assert(_, rest)
+        | Memcpy(_, _, _, rest)
+        | Memset(_, _, _, rest)
+        | Yield(rest) => rest,
+    };
+    init_flow(rest, written, bad, jumps)
+}
+
+// Rejects programs that may read a register before writing it, on some path
+// from block 0. A block that several paths reach only counts the registers
+// that every one of them writes. `entry` is the registers that hold a value
+// when the program starts.
+pub fn check_initialized(
+    blocks: &HashMap<i32, syntax::Instr>,
+    entry: HashSet<syntax::Reg>,
+) -> Result<(), Error> {
+    let mut ins: HashMap<i32, HashSet<syntax::Reg>> = HashMap::new();
+    ins.insert(0, entry);
+    let mut work = vec![0];
+    while let Some(id) = work.pop() {
+        let instr = match blocks.get(&id) {
+            Some(instr) => instr,
+            None => continue,
+        };
+        let mut jumps = Vec::new();
+        init_flow(instr, ins[&id].clone(), &mut Vec::new(), &mut jumps);
+        for (jump, written) in jumps {
+            let targets: Vec<i32> = match jump {
+                Jump::To(n) => vec![n],
+                Jump::Anywhere => blocks.keys().cloned().collect(),
+            };
+            for target in targets {
+                let changed = match ins.get_mut(&target) {
+                    None => {
+                        ins.insert(target, written.clone());
+                        true
+                    }
+                    Some(set) => {
+                        let before = set.len();
+                        set.retain(|r| written.contains(r));
+                        set.len() != before
+                    }
+                };
+                if changed {
+                    work.push(target);
+                }
+            }
+        }
+    }
+    let mut ids: Vec<&i32> =
+        ins.keys().filter(|id| blocks.contains_key(id)).collect();
+    ids.sort();
+    for id in ids {
+        let mut bad = Vec::new();
+        init_flow(&blocks[id], ins[id].clone(), &mut bad, &mut Vec::new());
+        if let Some(r) = bad.first() {
+            return Err(Error::Usage(format!(
+                "block {} may read r{} before writing it",
+                id, r
+            )));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,184 @@
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+// Runs the ilvm binary with `args`, feeding it `stdin`.
+fn run(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_ilvm"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+// Like `run`, but returns what it printed.
+fn ilvm(args: &[&str], stdin: &str) -> String {
+    String::from_utf8(run(args, stdin).stdout).unwrap()
+}
+
+// Writes `code` to a file named `name` in a temporary directory, returning
+// its path.
+fn source_file(name: &str, code: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("ilvm-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    fs::write(&path, code).unwrap();
+    path
+}
+
+#[test]
+fn test_program_from_stdin() {
+    let code = "block 0 { r0 = 6 * 7; exit(r0); }";
+    let expected = "Normal termination. Result = 42\n";
+    assert_eq!(ilvm(&[], code), expected);
+    assert_eq!(ilvm(&["-"], code), expected);
+}
+
+#[test]
+fn test_exit_code() {
+    let code = |program: &str| run(&["--exit-code"], program).status.code();
+    assert_eq!(code("block 0 { exit(42); }"), Some(42));
+    assert_eq!(code("block 0 { exit(1000); }"), Some(255));
+    assert_eq!(code("block 0 { exit(-7); }"), Some(0));
+    assert_eq!(code("block 0 { abort; }"), Some(1));
+    assert_eq!(run(&[], "block 0 { exit(42); }").status.code(), Some(0));
+}
+
+#[test]
+fn test_dump_on_error() {
+    let code =
+        "block 0 { r0 = malloc(2); *r0 = 5; r1 = 5000; *r1 = 7; exit(0); }";
+    let out = run(&["--dump-on-error"], code);
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("    r1 = 5000\n"), "{}", stderr);
+    assert!(stderr.contains("    1: [5, 0]\n"), "{}", stderr);
+    let out = run(&[], code);
+    assert!(!String::from_utf8(out.stderr).unwrap().contains("registers"));
+}
+
+#[test]
+fn test_breakpoint_log() {
+    let code = "block 0 { r0 = 3; goto(1); } \
+                block 1 { ifz r0 { exit(0); } else { r0 = r0 - 1; goto(1); } }";
+    let out = run(&["--break", "1", "--break", "0"], code);
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert_eq!(stderr.matches("breakpoint: block 1\n").count(), 4);
+    assert_eq!(stderr.matches("breakpoint: block 0\n").count(), 1);
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert_eq!(stdout, "Normal termination. Result = 0\n");
+    let out = ilvm(&["--break", "7"], code);
+    assert!(out.contains("breakpoint on block 7"), "{}", out);
+}
+
+#[test]
+fn test_multiple_files() {
+    let lib = source_file(
+        "lib.ilvm",
+        "block square { r0 = r1 * r1; ret(r0); }",
+    );
+    let main = source_file(
+        "main.ilvm",
+        "block main { r1 = 7; call(square); exit(r0); }",
+    );
+    let (lib, main) = (lib.to_str().unwrap(), main.to_str().unwrap());
+    assert_eq!(ilvm(&[main, lib], ""), "Normal termination. Result = 49\n");
+
+    let first = source_file("first.ilvm", "block 0 { goto(1); }");
+    let second = source_file("second.ilvm", "block 1 { exit(0); }");
+    let third = source_file("third.ilvm", "block 1 { exit(1); }");
+    let paths = [first.to_str().unwrap(), second.to_str().unwrap()];
+    assert_eq!(ilvm(&paths, ""), "Normal termination. Result = 0\n");
+    let paths = [paths[0], paths[1], third.to_str().unwrap()];
+    assert_eq!(
+        ilvm(&paths, ""),
+        format!(
+            "An error occurred.\nblock 1 is defined in both {} and {}\n",
+            paths[1], paths[2]
+        )
+    );
+}
+
+#[test]
+fn test_check() {
+    let code = "block 0 { r40 = 1; exit(r40); }";
+    let out = run(&["--check", "-r", "10"], code);
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert!(stdout.starts_with("highest register: r40\n"), "{}", stdout);
+    assert!(
+        stdout.contains("block 0 uses r40, but there are only 10 registers"),
+        "{}",
+        stdout
+    );
+    assert_eq!(out.status.code(), Some(1));
+    let out = ilvm(&["--check", "-r", "41"], code);
+    assert_eq!(out, "highest register: r40\nfits in 41 registers\n");
+}
+
+#[test]
+fn test_missing_file() {
+    let path = std::env::temp_dir().join("ilvm-no-such-file.ilvm");
+    let out = run(&[path.to_str().unwrap()], "");
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    let expected = format!("An error occurred.\n{}: ", path.display());
+    assert!(stdout.starts_with(&expected), "{}", stdout);
+    assert_eq!(out.status.code(), Some(1));
+}
+
+#[test]
+fn test_memory_limit() {
+    let code = "block 0 { exit(0); }";
+    for words in &["0", "1", "lots"] {
+        let out = run(&["-m", words], code);
+        let stdout = String::from_utf8(out.stdout).unwrap();
+        assert!(stdout.starts_with("An error occurred.\n"), "{}", stdout);
+        assert!(stdout.contains("--memory-limit"), "{}", stdout);
+        assert_eq!(out.status.code(), Some(1));
+    }
+    let out = ilvm(&["-m", "2"], code);
+    assert_eq!(out, "Normal termination. Result = 0\n");
+}
+
+#[test]
+fn test_deterministic() {
+    // Long enough that a microsecond timeout always stops it.
+    let code = "block 0 { r0 = 100000; goto(1); } \
+                block 1 { ifz r0 { print(\"done\"); exit(r1); } \
+                else { r1 = r1 + 2; r0 = r0 - 1; goto(1); } }";
+    let args = ["--deterministic", "--timeout", "0.000001"];
+    let first = run(&args, code);
+    let second = run(&args, code);
+    assert_eq!(first.stdout, second.stdout);
+    assert_eq!(first.stderr, second.stderr);
+    assert_eq!(first.status.code(), second.status.code());
+    let stdout = String::from_utf8(first.stdout).unwrap();
+    assert_eq!(stdout, "done\nNormal termination. Result = 200000\n");
+    let out = ilvm(&["--timeout", "0.000001"], code);
+    assert!(out.contains("timeout exceeded"), "{}", out);
+}
+
+#[test]
+fn test_json_result_output() {
+    // Everything the program prints is inside the one JSON object.
+    let code = "block 0 { print(\"hello\"); print(7); exit(7); }";
+    let out = ilvm(&["--json-result"], code);
+    assert_eq!(
+        out,
+        "{\"status\": \"exit\", \"code\": 7, \"message\": null, \
+         \"output\": \"hello\\n7\\n\"}\n"
+    );
+    let code = "block 0 { print(1); r0 = 1 / 0; exit(r0); }";
+    let out = ilvm(&["--json-result"], code);
+    assert_eq!(out.lines().count(), 1, "{}", out);
+    assert!(out.starts_with("{\"status\": \"error\""), "{}", out);
+    assert!(out.ends_with(", \"output\": \"1\\n\"}\n"), "{}", out);
+}
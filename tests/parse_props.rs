@@ -0,0 +1,201 @@
+extern crate ilvm;
+
+use ilvm::parser::parse;
+use ilvm::syntax::{format_blocks, Block, Instr, Op1, Op2, Printable, Val};
+use std::panic;
+
+// A small xorshift generator, so that every case can be rerun from its seed.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+const OPS: [Op2; 19] = [
+    Op2::Add,
+    Op2::Sub,
+    Op2::Mul,
+    Op2::Div,
+    Op2::Mod,
+    Op2::LT,
+    Op2::Eq,
+    Op2::GT,
+    Op2::LE,
+    Op2::GE,
+    Op2::NE,
+    Op2::And,
+    Op2::Or,
+    Op2::Xor,
+    Op2::Shl,
+    Op2::Shr,
+    Op2::Min,
+    Op2::Max,
+    Op2::Pow,
+];
+
+fn reg(rng: &mut Rng) -> usize {
+    rng.below(12)
+}
+
+fn val(rng: &mut Rng) -> Val {
+    match rng.below(6) {
+        0 | 1 => Val::Reg(reg(rng)),
+        2 => Val::Imm(i32::MIN),
+        3 => Val::Imm(i32::MAX),
+        4 => Val::Imm(rng.next() as i32),
+        _ => Val::Imm(rng.below(21) as i32 - 10),
+    }
+}
+
+fn name(rng: &mut Rng) -> String {
+    let chars = b"abzABZ059 .-\"\\";
+    (0..rng.below(7))
+        .map(|_| chars[rng.below(chars.len())] as char)
+        .collect()
+}
+
+fn printable(rng: &mut Rng) -> Printable {
+    match rng.below(5) {
+        0 => Printable::Id(name(rng)),
+        1 => Printable::Val(val(rng)),
+        2 => Printable::Hex(val(rng)),
+        3 => Printable::Seq(val(rng), val(rng)),
+        _ => Printable::Array(val(rng), val(rng)),
+    }
+}
+
+// An instruction that ends a block, which may be an `ifz` whose branches are
+// up to `size` instructions long.
+fn terminator(rng: &mut Rng, size: usize, depth: usize) -> Instr {
+    match rng.below(if depth < 3 { 7 } else { 6 }) {
+        0 => Instr::Goto(val(rng)),
+        1 => Instr::Exit(val(rng)),
+        2 => Instr::Abort(None),
+        3 => Instr::Abort(Some(name(rng))),
+        4 | 5 => Instr::Ret(val(rng)),
+        _ => {
+            let v = val(rng);
+            let (n, m) = (rng.below(size + 1), rng.below(size + 1));
+            let true_part = instrs(rng, n, depth + 1);
+            let false_part = instrs(rng, m, depth + 1);
+            Instr::IfZ(v, Box::new(true_part), Box::new(false_part))
+        }
+    }
+}
+
+// A sequence of `size` instructions, and then a terminator.
+fn instrs(rng: &mut Rng, size: usize, depth: usize) -> Instr {
+    if size == 0 {
+        return terminator(rng, 4, depth);
+    }
+    let kind = rng.below(24);
+    let r = reg(rng);
+    let rest = Box::new(instrs(rng, size - 1, depth));
+    match kind {
+        0 => {
+            let op = OPS[rng.below(OPS.len())].clone();
+            Instr::Op2(r, op, val(rng), val(rng), rest)
+        }
+        1 => Instr::Copy(r, val(rng), rest),
+        2 => Instr::Swap(r, reg(rng), rest),
+        3 => Instr::Load(r, val(rng), rest),
+        4 => Instr::Store(val(rng), val(rng), rest),
+        5 => Instr::LoadIdx(r, reg(rng), val(rng), rest),
+        6 => Instr::StoreIdx(r, val(rng), val(rng), rest),
+        7 => Instr::Malloc(r, val(rng), rest),
+        8 => Instr::Calloc(r, val(rng), rest),
+        9 => Instr::Realloc(r, reg(rng), val(rng), rest),
+        10 => Instr::Print(printable(rng), rest),
+        11 => Instr::Free(r, rest),
+        12 => Instr::Tag(name(rng), val(rng), rest),
+        13 => Instr::Capture(name(rng), val(rng), val(rng), rest),
+        14 => Instr::Assert(val(rng), rest),
+        15 => Instr::Memcpy(val(rng), val(rng), val(rng), rest),
+        16 => Instr::Memset(val(rng), val(rng), val(rng), rest),
+        17 => Instr::Spawn(val(rng), rest),
+        18 => Instr::Yield(rest),
+        19 => Instr::FetchAdd(r, reg(rng), val(rng), rest),
+        20 => Instr::Read(r, rest),
+        21 => {
+            let op = if rng.below(2) == 0 { Op1::Abs } else { Op1::Sign };
+            Instr::Op1(r, op, val(rng), rest)
+        }
+        _ => Instr::Call(val(rng), rest),
+    }
+}
+
+fn program(rng: &mut Rng) -> Vec<Block> {
+    let mut id = 0;
+    (0..1 + rng.below(4))
+        .map(|_| {
+            let size = rng.below(12);
+            let block = (id, instrs(rng, size, 0));
+            id += 1 + rng.below(3) as i32;
+            block
+        }).collect()
+}
+
+#[test]
+fn test_print_then_parse() {
+    for seed in 0..500 {
+        let blocks = program(&mut Rng::new(seed));
+        let source = format_blocks(&blocks);
+        match parse(&source) {
+            Ok(parsed) => {
+                assert_eq!(parsed, blocks, "seed {}:\n{}", seed, source)
+            }
+            Err(err) => panic!("seed {}: {}\n{}", seed, err, source),
+        }
+    }
+}
+
+// Pieces of programs, both sensible and not, to glue together at random.
+const PIECES: [&str; 32] = [
+    "block", "main", "0", "7", "{", "}", "(", ")", "[", "]", ";", ",", "=",
+    "-", "+", "*", "==", "r0", "r1", "ifz", "else", "while", "goto",
+    "exit", "malloc", "print", "\"hi\"", "2147483648", "99999999999",
+    "0x", "1_", "r99999999999999999999",
+];
+
+#[test]
+fn test_parse_never_panics() {
+    for seed in 0..2000 {
+        let mut rng = Rng::new(seed);
+        let source: String = match seed % 3 {
+            0 => (0..rng.below(40))
+                .map(|_| {
+                    let sep = if rng.below(3) == 0 { "" } else { " " };
+                    format!("{}{}", PIECES[rng.below(PIECES.len())], sep)
+                }).collect(),
+            1 => (0..rng.below(40))
+                .map(|_| (32 + rng.below(95)) as u8 as char)
+                .collect(),
+            // A valid program with a piece put in or taken out somewhere.
+            _ => {
+                let mut source = format_blocks(&program(&mut rng));
+                let at = rng.below(source.len());
+                if rng.below(2) == 0 {
+                    source.insert_str(at, PIECES[rng.below(PIECES.len())]);
+                } else {
+                    source.remove(at);
+                }
+                source
+            }
+        };
+        let result = panic::catch_unwind(|| parse(&source).is_ok());
+        assert!(result.is_ok(), "seed {}: panic on {:?}", seed, source);
+    }
+}
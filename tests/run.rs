@@ -0,0 +1,23 @@
+extern crate ilvm;
+
+#[test]
+fn test_run() {
+    let r = ilvm::run(
+        r#"
+        block 0 {
+            r0 = malloc(1);
+            *r0 = 6;
+            r1 = *r0;
+            r1 = r1 * 7;
+            exit(r1);
+        }"#,
+        100,
+        4,
+    );
+    assert_eq!(r.unwrap(), 42);
+}
+
+#[test]
+fn test_run_register_limit() {
+    assert!(ilvm::run("block 0 { exit(r4); }", 100, 4).is_err());
+}